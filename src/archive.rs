@@ -0,0 +1,891 @@
+//! A writer for static libraries, used instead of `ar` or `lib.exe` when the
+//! built-in archiver is enabled, and a reader for the members of the libraries
+//! merged into them.
+//!
+//! Three flavors of the `ar` format are supported: the GNU one (used for ELF
+//! and MinGW COFF objects), the BSD one (used for Mach-O objects) and the COFF
+//! one (used by MSVC for `.lib` files). Each of them gets a symbol table so
+//! that linkers can find members without running `ranlib`.
+//!
+//! The output only depends on the contents and names of the members: owner
+//! ids are zero, modes are fixed and timestamps are whatever the caller passes
+//! in, so archives are reproducible byte for byte.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str;
+
+const MAGIC: [u8; 8] = *b"!<arch>\n";
+const HEADER_LEN: usize = 60;
+
+/// The flavor of archive to write.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    /// GNU `ar`, with a `/` symbol table and a `//` long name table.
+    Gnu,
+    /// BSD `ar` as used on Apple platforms, with a `__.SYMDEF` symbol table
+    /// and names stored in front of the member data.
+    Bsd,
+    /// The COFF import library format of MSVC, with both linker members.
+    Coff,
+}
+
+struct Member {
+    name: String,
+    data: Vec<u8>,
+    symbols: Vec<Vec<u8>>,
+}
+
+/// Writes the archive `dst` containing the object files `objects`, in order,
+/// followed by the members of the archives `archives`.
+///
+/// Objects are named after their file names, and the headers of all members
+/// carry `mtime` as the modification time. Members whose names are taken
+/// are renamed with `unique_name`. Returns the names of the members whose
+/// symbols could not be read, which are left out of the symbol table.
+pub fn write(
+    dst: &Path,
+    format: Format,
+    objects: &[&Path],
+    archives: &[&Path],
+    mtime: u64,
+) -> io::Result<Vec<String>> {
+    let mut members = Vec::with_capacity(objects.len());
+    for object in objects {
+        let data = read_file(object)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", object.display(), e)))?;
+        let name = match object.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a file", object.display()),
+                ));
+            }
+        };
+        members.push((name, data));
+    }
+    for archive in archives {
+        members.extend(read(archive)?);
+    }
+
+    let mut taken = HashSet::new();
+    let mut unreadable = Vec::new();
+    let members = members
+        .into_iter()
+        .map(|(name, data)| {
+            let name = unique_name(&mut taken, &name);
+            let symbols = symbols(&data).unwrap_or_else(|| {
+                unreadable.push(name.clone());
+                Vec::new()
+            });
+            Member {
+                name: name,
+                data: data,
+                symbols: symbols,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let archive = match format {
+        Format::Gnu => gnu_archive(&members, mtime),
+        Format::Bsd => bsd_archive(&members, mtime),
+        Format::Coff => coff_archive(&members, mtime),
+    };
+    File::create(dst)?.write_all(&archive)?;
+    Ok(unreadable)
+}
+
+/// Returns `name`, or `1-name`, `2-name` and so on if it is already taken,
+/// so that members with the same name don't replace each other when they are
+/// extracted.
+pub fn unique_name(taken: &mut HashSet<String>, name: &str) -> String {
+    let mut unique = name.to_string();
+    let mut i = 1;
+    while !taken.insert(unique.clone()) {
+        unique = format!("{}-{}", i, name);
+        i += 1;
+    }
+    unique
+}
+
+/// Reads the names and contents of the members of the archive `path`, in
+/// order, skipping its symbol tables and long name table.
+///
+/// GNU, BSD and COFF archives are supported, but not thin ones.
+pub fn read(path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let data = read_file(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    parse(&data).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a supported static library", path.display()),
+        )
+    })
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn parse(data: &[u8]) -> Option<Vec<(String, Vec<u8>)>> {
+    if !data.starts_with(&MAGIC) {
+        return None;
+    }
+    let mut members = Vec::new();
+    let mut long_names: &[u8] = &[];
+    let mut offset = MAGIC.len();
+    while offset < data.len() {
+        let header = try_opt!(data.get(offset..offset + HEADER_LEN));
+        if &header[58..] != b"`\n" {
+            return None;
+        }
+        let name = try_opt!(str::from_utf8(&header[..16]).ok()).trim_right();
+        let size = try_opt!(str::from_utf8(&header[48..58]).ok());
+        let size: usize = try_opt!(size.trim_right().parse().ok());
+        let start = offset + HEADER_LEN;
+        let mut contents = try_opt!(data.get(start..start + size));
+        offset = padded(start + size, 2);
+
+        let name = if name == "/" || name == "/SYM64/" || name.starts_with("/<") {
+            // Symbol tables, which are rebuilt when writing.
+            continue;
+        } else if name == "//" {
+            long_names = contents;
+            continue;
+        } else if name.starts_with('/') {
+            // A GNU or COFF long name, terminated by `/\n` or a NUL.
+            let index = try_opt!(name[1..].parse::<usize>().ok());
+            let rest = try_opt!(long_names.get(index..));
+            let end = rest
+                .iter()
+                .position(|&b| b == b'/' || b == b'\n' || b == 0)
+                .unwrap_or(rest.len());
+            String::from_utf8_lossy(&rest[..end]).into_owned()
+        } else if name.starts_with("#1/") {
+            // A BSD name stored in front of the data, padded with NULs.
+            let len = try_opt!(name[3..].parse::<usize>().ok());
+            let stored = try_opt!(contents.get(..len));
+            contents = &contents[len..];
+            let end = stored.iter().position(|&b| b == 0).unwrap_or(len);
+            String::from_utf8_lossy(&stored[..end]).into_owned()
+        } else {
+            name.trim_right_matches('/').to_string()
+        };
+        if name.starts_with("__.SYMDEF") {
+            continue;
+        }
+        members.push((name, contents.to_vec()));
+    }
+    Some(members)
+}
+
+fn gnu_archive(members: &[Member], mtime: u64) -> Vec<u8> {
+    let (long_names, names) = long_name_table(members, b"/\n");
+    let symbol_count: usize = members.iter().map(|m| m.symbols.len()).sum();
+    let symbol_names_len: usize = members
+        .iter()
+        .flat_map(|m| m.symbols.iter())
+        .map(|s| s.len() + 1)
+        .sum();
+    // Like binutils, count the padding of the symbol table in its size.
+    let symtab_len = padded(4 + 4 * symbol_count + symbol_names_len, 2);
+
+    let mut offset = MAGIC.len() + HEADER_LEN + symtab_len;
+    if !long_names.is_empty() {
+        offset += HEADER_LEN + padded(long_names.len(), 2);
+    }
+    let offsets = member_offsets(members, offset, |m| padded(m.data.len(), 2));
+
+    let mut out = MAGIC.to_vec();
+    header(&mut out, b"/", mtime, "0", symtab_len);
+    out.extend_from_slice(&be32(symbol_count));
+    for (member, &offset) in members.iter().zip(&offsets) {
+        for _ in &member.symbols {
+            out.extend_from_slice(&be32(offset));
+        }
+    }
+    for symbol in members.iter().flat_map(|m| m.symbols.iter()) {
+        out.extend_from_slice(symbol);
+        out.push(0);
+    }
+    pad(&mut out, 2, 0);
+
+    write_long_names_and_members(&mut out, members, &long_names, &names, mtime);
+    out
+}
+
+fn bsd_archive(members: &[Member], mtime: u64) -> Vec<u8> {
+    // ld64 wants the object data 8-byte aligned, so the names are padded with
+    // NULs such that headers and data start at multiples of 8, and so are the
+    // member sizes.
+    fn name_len(name: &str) -> usize {
+        padded(name.len() + 4, 8) - 4
+    }
+
+    let mut strtab = Vec::new();
+    let mut symbols = Vec::new();
+    for (i, member) in members.iter().enumerate() {
+        for symbol in &member.symbols {
+            symbols.push((strtab.len(), i));
+            strtab.extend_from_slice(symbol);
+            strtab.push(0);
+        }
+    }
+    let strtab_len = strtab.len();
+    pad(&mut strtab, 8, 0);
+    let symdef_name = "__.SYMDEF";
+    let symdef_len = 4 + 8 * symbols.len() + 4 + strtab.len();
+
+    let offset = MAGIC.len() + HEADER_LEN + name_len(symdef_name) + symdef_len;
+    let offsets = member_offsets(members, offset, |m| {
+        name_len(&m.name) + padded(m.data.len(), 8)
+    });
+
+    let mut out = MAGIC.to_vec();
+    bsd_header(
+        &mut out,
+        symdef_name,
+        name_len(symdef_name),
+        mtime,
+        "0",
+        symdef_len,
+    );
+    out.extend_from_slice(&le32(8 * symbols.len()));
+    for &(strx, member) in &symbols {
+        out.extend_from_slice(&le32(strx));
+        out.extend_from_slice(&le32(offsets[member]));
+    }
+    out.extend_from_slice(&le32(strtab_len));
+    out.extend_from_slice(&strtab);
+
+    for member in members {
+        let data_len = padded(member.data.len(), 8);
+        bsd_header(
+            &mut out,
+            &member.name,
+            name_len(&member.name),
+            mtime,
+            "644",
+            data_len,
+        );
+        out.extend_from_slice(&member.data);
+        pad(&mut out, 8, b'\n');
+    }
+    out
+}
+
+fn coff_archive(members: &[Member], mtime: u64) -> Vec<u8> {
+    let (long_names, names) = long_name_table(members, b"\0");
+    let symbol_count: usize = members.iter().map(|m| m.symbols.len()).sum();
+    let symbol_names_len: usize = members
+        .iter()
+        .flat_map(|m| m.symbols.iter())
+        .map(|s| s.len() + 1)
+        .sum();
+    let first_len = 4 + 4 * symbol_count + symbol_names_len;
+    let second_len = 4 + 4 * members.len() + 4 + 2 * symbol_count + symbol_names_len;
+
+    let mut offset = MAGIC.len();
+    offset += HEADER_LEN + padded(first_len, 2);
+    offset += HEADER_LEN + padded(second_len, 2);
+    if !long_names.is_empty() {
+        offset += HEADER_LEN + padded(long_names.len(), 2);
+    }
+    let offsets = member_offsets(members, offset, |m| padded(m.data.len(), 2));
+
+    let mut out = MAGIC.to_vec();
+
+    // The first linker member lists symbols in member order, with big endian
+    // offsets of the members defining them.
+    header(&mut out, b"/", mtime, "0", first_len);
+    out.extend_from_slice(&be32(symbol_count));
+    for (member, &offset) in members.iter().zip(&offsets) {
+        for _ in &member.symbols {
+            out.extend_from_slice(&be32(offset));
+        }
+    }
+    for symbol in members.iter().flat_map(|m| m.symbols.iter()) {
+        out.extend_from_slice(symbol);
+        out.push(0);
+    }
+    pad(&mut out, 2, b'\n');
+
+    // The second one has little endian member offsets, and sorted symbols
+    // referring to them by (one-based) index.
+    let mut sorted = members
+        .iter()
+        .enumerate()
+        .flat_map(|(i, m)| m.symbols.iter().map(move |s| (s, i + 1)))
+        .collect::<Vec<_>>();
+    sorted.sort();
+    header(&mut out, b"/", mtime, "0", second_len);
+    out.extend_from_slice(&le32(members.len()));
+    for &offset in &offsets {
+        out.extend_from_slice(&le32(offset));
+    }
+    out.extend_from_slice(&le32(symbol_count));
+    for &(_, index) in &sorted {
+        out.extend_from_slice(&[index as u8, (index >> 8) as u8]);
+    }
+    for &(symbol, _) in &sorted {
+        out.extend_from_slice(symbol);
+        out.push(0);
+    }
+    pad(&mut out, 2, b'\n');
+
+    write_long_names_and_members(&mut out, members, &long_names, &names, mtime);
+    out
+}
+
+/// Builds the `//` member holding names longer than 15 bytes, each followed
+/// by `terminator`, and returns it along with the header name of every member.
+fn long_name_table(members: &[Member], terminator: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let mut long_names = Vec::new();
+    let mut names = Vec::new();
+    for member in members {
+        if member.name.len() <= 15 {
+            names.push(format!("{}/", member.name).into_bytes());
+        } else {
+            names.push(format!("/{}", long_names.len()).into_bytes());
+            long_names.extend_from_slice(member.name.as_bytes());
+            long_names.extend_from_slice(terminator);
+        }
+    }
+    (long_names, names)
+}
+
+fn write_long_names_and_members(
+    out: &mut Vec<u8>,
+    members: &[Member],
+    long_names: &[u8],
+    names: &[Vec<u8>],
+    mtime: u64,
+) {
+    if !long_names.is_empty() {
+        // The long name table has no timestamp, owner or mode, and its size
+        // includes the padding.
+        field(out, b"//", 48);
+        field(out, padded(long_names.len(), 2).to_string().as_bytes(), 10);
+        out.extend_from_slice(b"`\n");
+        out.extend_from_slice(long_names);
+        pad(out, 2, b'\n');
+    }
+    for (member, name) in members.iter().zip(names) {
+        header(out, name, mtime, "644", member.data.len());
+        out.extend_from_slice(&member.data);
+        pad(out, 2, b'\n');
+    }
+}
+
+/// Returns the offset of the header of each member, given the offset of the
+/// first one and the size each one takes after its header.
+fn member_offsets<F>(members: &[Member], mut offset: usize, size: F) -> Vec<usize>
+where
+    F: Fn(&Member) -> usize,
+{
+    members
+        .iter()
+        .map(|member| {
+            let this = offset;
+            offset += HEADER_LEN + size(member);
+            this
+        })
+        .collect()
+}
+
+fn header(out: &mut Vec<u8>, name: &[u8], mtime: u64, mode: &str, size: usize) {
+    field(out, name, 16);
+    field(out, mtime.to_string().as_bytes(), 12);
+    field(out, b"0", 6);
+    field(out, b"0", 6);
+    field(out, mode.as_bytes(), 8);
+    field(out, size.to_string().as_bytes(), 10);
+    out.extend_from_slice(b"`\n");
+}
+
+/// Writes a BSD header for `name` stored in front of the data, padded with
+/// NULs to `name_len` bytes.
+fn bsd_header(out: &mut Vec<u8>, name: &str, name_len: usize, mtime: u64, mode: &str, size: usize) {
+    header(
+        out,
+        format!("#1/{}", name_len).as_bytes(),
+        mtime,
+        mode,
+        name_len + size,
+    );
+    out.extend_from_slice(name.as_bytes());
+    out.extend((name.len()..name_len).map(|_| 0));
+}
+
+fn field(out: &mut Vec<u8>, value: &[u8], width: usize) {
+    out.extend_from_slice(value);
+    out.extend((value.len()..width).map(|_| b' '));
+}
+
+fn pad(out: &mut Vec<u8>, align: usize, byte: u8) {
+    let len = padded(out.len(), align);
+    out.resize(len, byte);
+}
+
+/// Rounds `len` up to a multiple of `align`, which must be a power of two.
+fn padded(len: usize, align: usize) -> usize {
+    (len + align - 1) & !(align - 1)
+}
+
+fn be32(n: usize) -> [u8; 4] {
+    let n = n as u32;
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn le32(n: usize) -> [u8; 4] {
+    let n = n as u32;
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+/// Returns the names of the global symbols defined by an ELF, COFF or Mach-O
+/// object, or by a COFF import member. Returns `None` for anything else (e.g.
+/// LLVM bitcode or objects compiled with `/GL`) and for malformed objects.
+fn symbols(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    if data.starts_with(b"\x7fELF") {
+        elf_symbols(data)
+    } else if data.len() >= 4 && is_macho(data) {
+        macho_symbols(data)
+    } else {
+        coff_symbols(data)
+    }
+}
+
+/// Little or big endian reads of fixed size fields, failing on truncated data.
+#[derive(Copy, Clone)]
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Option<&'a [u8]> {
+        self.data.get(offset..try_opt!(offset.checked_add(len)))
+    }
+
+    fn u8(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).cloned()
+    }
+
+    /// Reads an unsigned integer of `len` bytes.
+    fn uint(&self, offset: usize, len: usize) -> Option<u64> {
+        let b = try_opt!(self.bytes(offset, len));
+        let push = |n: u64, byte: &u8| n << 8 | u64::from(*byte);
+        Some(if self.big_endian {
+            b.iter().fold(0, push)
+        } else {
+            b.iter().rev().fold(0, push)
+        })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        self.uint(offset, 2).map(|n| n as u16)
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        self.uint(offset, 4).map(|n| n as u32)
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        self.uint(offset, 8)
+    }
+
+    /// Reads a 32 or 64 bit word as a `usize`.
+    fn word(&self, offset: usize, is_64: bool) -> Option<usize> {
+        if is_64 {
+            self.u64(offset).map(|n| n as usize)
+        } else {
+            self.u32(offset).map(|n| n as usize)
+        }
+    }
+
+    /// Reads a NUL-terminated string.
+    fn c_str(&self, offset: usize) -> Option<&'a [u8]> {
+        let rest = try_opt!(self.data.get(offset..));
+        let len = try_opt!(rest.iter().position(|&b| b == 0));
+        Some(&rest[..len])
+    }
+}
+
+fn elf_symbols(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    const SHT_SYMTAB: u32 = 2;
+    const STB_GLOBAL: u8 = 1;
+    const STB_WEAK: u8 = 2;
+    const STB_GNU_UNIQUE: u8 = 10;
+    const STT_SECTION: u8 = 3;
+    const STT_FILE: u8 = 4;
+
+    let is_64 = *try_opt!(data.get(4)) == 2;
+    let r = Reader {
+        data: data,
+        big_endian: *try_opt!(data.get(5)) == 2,
+    };
+    let (shoff, shentsize, shnum) = if is_64 {
+        (
+            try_opt!(r.word(0x28, true)),
+            try_opt!(r.u16(0x3a)),
+            try_opt!(r.u16(0x3c)) as usize,
+        )
+    } else {
+        (
+            try_opt!(r.word(0x20, false)),
+            try_opt!(r.u16(0x2e)),
+            try_opt!(r.u16(0x30)) as usize,
+        )
+    };
+    let section = |i: usize| shoff + i * shentsize as usize;
+    // (type, offset, size, link) of a section header
+    let section_info = |i: usize| -> Option<(u32, usize, usize, usize)> {
+        let s = section(i);
+        if is_64 {
+            Some((
+                try_opt!(r.u32(s + 4)),
+                try_opt!(r.word(s + 24, true)),
+                try_opt!(r.word(s + 32, true)),
+                try_opt!(r.u32(s + 40)) as usize,
+            ))
+        } else {
+            Some((
+                try_opt!(r.u32(s + 4)),
+                try_opt!(r.word(s + 16, false)),
+                try_opt!(r.word(s + 20, false)),
+                try_opt!(r.u32(s + 24)) as usize,
+            ))
+        }
+    };
+
+    // With too many sections for the header, their number is the size of
+    // the first one.
+    let shnum = match shnum {
+        0 if shoff != 0 => try_opt!(section_info(0)).2,
+        n => n,
+    };
+
+    let mut symbols = Vec::new();
+    for i in 0..shnum {
+        let (kind, offset, size, link) = try_opt!(section_info(i));
+        if kind != SHT_SYMTAB {
+            continue;
+        }
+        let (_, strtab, _, _) = try_opt!(section_info(link));
+        let entsize = if is_64 { 24 } else { 16 };
+        // The first symbol is always the null one.
+        for i in 1..size / entsize {
+            let sym = offset + i * entsize;
+            let (name, info, shndx) = if is_64 {
+                (
+                    try_opt!(r.u32(sym)),
+                    try_opt!(r.u8(sym + 4)),
+                    try_opt!(r.u16(sym + 6)),
+                )
+            } else {
+                (
+                    try_opt!(r.u32(sym)),
+                    try_opt!(r.u8(sym + 12)),
+                    try_opt!(r.u16(sym + 14)),
+                )
+            };
+            let bind = info >> 4;
+            let kind = info & 0xf;
+            if shndx == 0 || kind == STT_SECTION || kind == STT_FILE {
+                continue;
+            }
+            if bind == STB_GLOBAL || bind == STB_WEAK || bind == STB_GNU_UNIQUE {
+                symbols.push(try_opt!(r.c_str(strtab + name as usize)).to_vec());
+            }
+        }
+    }
+    Some(symbols)
+}
+
+fn is_macho(data: &[u8]) -> bool {
+    [false, true].iter().any(|&big_endian| {
+        let r = Reader {
+            data: data,
+            big_endian: big_endian,
+        };
+        r.u32(0) == Some(0xfeed_face) || r.u32(0) == Some(0xfeed_facf)
+    })
+}
+
+fn macho_symbols(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    const LC_SYMTAB: u32 = 2;
+    const N_STAB: u8 = 0xe0;
+    const N_PEXT: u8 = 0x10;
+    const N_TYPE: u8 = 0x0e;
+    const N_EXT: u8 = 0x01;
+    const N_UNDF: u8 = 0x0;
+    const N_ABS: u8 = 0x2;
+    const N_SECT: u8 = 0xe;
+
+    let magic = Reader {
+        data: data,
+        big_endian: true,
+    }
+    .u32(0);
+    let big_endian = try_opt!(magic) & !1 == 0xfeed_face;
+    let r = Reader {
+        data: data,
+        big_endian: big_endian,
+    };
+    let is_64 = try_opt!(r.u32(0)) == 0xfeed_facf;
+    let ncmds = try_opt!(r.u32(16));
+    let mut cmd = if is_64 { 32 } else { 28 };
+
+    let mut symbols = Vec::new();
+    for _ in 0..ncmds {
+        let (kind, size) = (try_opt!(r.u32(cmd)), try_opt!(r.u32(cmd + 4)) as usize);
+        if kind == LC_SYMTAB {
+            let symoff = try_opt!(r.u32(cmd + 8)) as usize;
+            let nsyms = try_opt!(r.u32(cmd + 12)) as usize;
+            let stroff = try_opt!(r.u32(cmd + 16)) as usize;
+            let entsize = if is_64 { 16 } else { 12 };
+            for i in 0..nsyms {
+                let sym = symoff + i * entsize;
+                let strx = try_opt!(r.u32(sym)) as usize;
+                let kind = try_opt!(r.u8(sym + 4));
+                let value = try_opt!(r.word(sym + 8, is_64));
+                if kind & N_STAB != 0 || kind & N_PEXT != 0 || kind & N_EXT == 0 {
+                    continue;
+                }
+                let defined = match kind & N_TYPE {
+                    N_SECT | N_ABS => true,
+                    // Common symbols are undefined ones with a size.
+                    N_UNDF => value != 0,
+                    _ => false,
+                };
+                if defined {
+                    symbols.push(try_opt!(r.c_str(stroff + strx)).to_vec());
+                }
+            }
+        }
+        if size == 0 {
+            return None;
+        }
+        cmd += size;
+    }
+    Some(symbols)
+}
+
+fn coff_symbols(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    const IMAGE_SYM_CLASS_EXTERNAL: u8 = 2;
+    const MACHINES: [u16; 5] = [0x14c, 0x1c0, 0x1c4, 0x8664, 0xaa64];
+    const BIGOBJ_CLASS_ID: [u8; 16] = [
+        0xc7, 0xa1, 0xba, 0xd1, 0xee, 0xba, 0xa9, 0x4b, 0xaf, 0x20, 0xfa, 0xf6, 0x6a, 0xa4, 0xdc,
+        0xb8,
+    ];
+
+    let r = Reader {
+        data: data,
+        big_endian: false,
+    };
+    // Import members and anonymous objects start with 0 and 0xffff where
+    // objects have their machine and number of sections.
+    let bigobj = if try_opt!(r.u16(0)) == 0 && try_opt!(r.u16(2)) == 0xffff {
+        if try_opt!(r.u16(4)) == 0 {
+            return import_symbols(r);
+        }
+        // Other anonymous objects, e.g. those compiled with `/GL`, can only
+        // be read by the compiler which wrote them.
+        if try_opt!(r.bytes(12, 16)) != &BIGOBJ_CLASS_ID[..] {
+            return None;
+        }
+        true
+    } else if MACHINES.contains(&try_opt!(r.u16(0))) {
+        false
+    } else {
+        return None;
+    };
+    let (symtab, nsyms, entsize) = if bigobj {
+        (
+            try_opt!(r.u32(48)) as usize,
+            try_opt!(r.u32(52)) as usize,
+            20,
+        )
+    } else {
+        (
+            try_opt!(r.u32(8)) as usize,
+            try_opt!(r.u32(12)) as usize,
+            18,
+        )
+    };
+    let strtab = symtab + nsyms * entsize;
+
+    let mut symbols = Vec::new();
+    let mut i = 0;
+    while i < nsyms {
+        let sym = symtab + i * entsize;
+        let value = try_opt!(r.u32(sym + 8));
+        let section = if bigobj {
+            try_opt!(r.u32(sym + 12)) as i32
+        } else {
+            i32::from(try_opt!(r.u16(sym + 12)) as i16)
+        };
+        let class = try_opt!(r.u8(sym + entsize - 2));
+        let aux = try_opt!(r.u8(sym + entsize - 1)) as usize;
+        i += 1 + aux;
+
+        // Section 0 is undefined (or common, given a size), and -2 is debug
+        // information.
+        let defined = match section {
+            0 => value != 0,
+            -2 => false,
+            _ => true,
+        };
+        if class != IMAGE_SYM_CLASS_EXTERNAL || !defined {
+            continue;
+        }
+        let name = try_opt!(r.bytes(sym, 8));
+        let name = if name[..4] == [0, 0, 0, 0] {
+            try_opt!(r.c_str(strtab + try_opt!(r.u32(sym + 4)) as usize))
+        } else {
+            let len = name.iter().position(|&b| b == 0).unwrap_or(8);
+            &name[..len]
+        };
+        symbols.push(name.to_vec());
+    }
+    Some(symbols)
+}
+
+/// Returns the symbols of a short import member, as written by `lib.exe` for
+/// each function or variable exported by a DLL: `__imp_` followed by its name,
+/// and the name itself unless it is data.
+fn import_symbols(r: Reader) -> Option<Vec<Vec<u8>>> {
+    const IMPORT_OBJECT_DATA: u16 = 1;
+
+    let name = try_opt!(r.c_str(20));
+    let mut symbols = vec![[&b"__imp_"[..], name].concat()];
+    if try_opt!(r.u16(18)) & 0x3 != IMPORT_OBJECT_DATA {
+        symbols.push(name.to_vec());
+    }
+    Some(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(symbols: Option<Vec<Vec<u8>>>) -> Vec<String> {
+        let mut symbols = symbols
+            .unwrap()
+            .into_iter()
+            .map(|s| String::from_utf8(s).unwrap())
+            .collect::<Vec<_>>();
+        symbols.sort();
+        symbols
+    }
+
+    fn expected() -> Vec<String> {
+        vec!["a_long_symbol_name".into(), "cvar".into(), "foo".into()]
+    }
+
+    #[test]
+    fn elf() {
+        assert_eq!(
+            sorted(symbols(include_bytes!("../tests/objects/elf64.o"))),
+            expected()
+        );
+        assert_eq!(
+            sorted(symbols(include_bytes!("../tests/objects/elf32.o"))),
+            expected()
+        );
+    }
+
+    #[test]
+    fn elf_extended_section_numbering() {
+        let mut data = include_bytes!("../tests/objects/elf64.o").to_vec();
+        let shoff = Reader {
+            data: &data,
+            big_endian: false,
+        }
+        .u64(0x28)
+        .unwrap() as usize;
+        let shnum = [data[0x3c], data[0x3d]];
+        data[0x3c..0x3e].copy_from_slice(&[0, 0]);
+        data[shoff + 32..shoff + 34].copy_from_slice(&shnum);
+        assert_eq!(sorted(symbols(&data)), expected());
+    }
+
+    #[test]
+    fn macho() {
+        assert_eq!(
+            sorted(symbols(include_bytes!("../tests/objects/macho.o"))),
+            expected()
+        );
+    }
+
+    #[test]
+    fn coff() {
+        assert_eq!(
+            sorted(symbols(include_bytes!("../tests/objects/coff.obj"))),
+            expected()
+        );
+        assert_eq!(
+            sorted(symbols(include_bytes!("../tests/objects/bigobj.obj"))),
+            expected()
+        );
+    }
+
+    #[test]
+    fn coff_import() {
+        let member = |kind: u8| {
+            let mut data = vec![0, 0, 0xff, 0xff, 0, 0, 0x64, 0x86, 0, 0, 0, 0];
+            data.extend_from_slice(&[12, 0, 0, 0, 0, 0, kind, 0]);
+            data.extend_from_slice(b"foo\0bar.dll\0");
+            data
+        };
+        assert_eq!(sorted(symbols(&member(0))), vec!["__imp_foo", "foo"]);
+        assert_eq!(sorted(symbols(&member(1))), vec!["__imp_foo"]);
+    }
+
+    #[test]
+    fn unreadable() {
+        // An object compiled with `/GL`, whose header is followed by data
+        // only the compiler understands.
+        let mut anonymous = vec![0, 0, 0xff, 0xff, 1, 0, 0x64, 0x86];
+        anonymous.resize(64, 0x38);
+        assert_eq!(symbols(&anonymous), None);
+        assert_eq!(symbols(b"BC\xc0\xde"), None);
+        assert_eq!(symbols(b"not really an object"), None);
+
+        let elf = include_bytes!("../tests/objects/elf64.o");
+        assert_eq!(symbols(&elf[..elf.len() / 2]), None);
+    }
+
+    #[test]
+    fn read_written() {
+        let members = vec![
+            Member {
+                name: "short.o".to_string(),
+                data: b"one".to_vec(),
+                symbols: Vec::new(),
+            },
+            Member {
+                name: "a_rather_long_name.o".to_string(),
+                data: b"two\n".to_vec(),
+                symbols: vec![b"foo".to_vec()],
+            },
+        ];
+        for archive in &[
+            gnu_archive(&members, 0),
+            bsd_archive(&members, 0),
+            coff_archive(&members, 0),
+        ] {
+            let read = parse(archive).unwrap();
+            assert_eq!(read.len(), 2);
+            assert_eq!(read[0].0, "short.o");
+            assert!(read[0].1.starts_with(b"one"));
+            assert_eq!(read[1].0, "a_rather_long_name.o");
+            assert!(read[1].1.starts_with(b"two\n"));
+        }
+    }
+}
@@ -2,10 +2,20 @@
 
 use std::env;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::path::PathBuf;
 
 fn main() {
+    if env::args().any(|a| a == "--print-targets") {
+        println!("  Registered Targets:");
+        let targets = env::var("GCCTEST_TARGETS").unwrap_or_else(|_| "x86-64".to_string());
+        for target in targets.split(',') {
+            println!("    {:<10} - {}", target, target);
+        }
+        return;
+    }
+
     let out_dir = PathBuf::from(env::var_os("GCCTEST_OUT_DIR").unwrap());
     for i in 0.. {
         let candidate = out_dir.join(format!("out{}", i));
@@ -13,11 +23,33 @@ fn main() {
             continue;
         }
         let mut f = File::create(candidate).unwrap();
-        for arg in env::args().skip(1) {
+        for arg in env::args() {
             writeln!(f, "{}", arg).unwrap();
         }
 
         File::create(out_dir.join("libfoo.a")).unwrap();
+        // Like GCC, warn about unknown flags and C++ standards given for C, so
+        // that the checks of flags can be tested.
+        let c_source = env::args().any(|a| a.ends_with(".c"));
+        for arg in env::args() {
+            if arg.contains("does-not-exist") || (c_source && arg.starts_with("-std=c++")) {
+                let warning = format!("cc1: warning: unsupported option '{}'\n", arg);
+                io::stderr().write_all(warning.as_bytes()).unwrap();
+            }
+        }
+        // Only compilations are passed a response file.
+        let compiling = env::args().any(|a| a == "-c" || a == "/c" || a.starts_with('@'));
+        if let (true, Some(stdout)) = (compiling, env::var_os("GCCTEST_STDOUT")) {
+            print!("{}", stdout.to_str().unwrap());
+        }
+        if let (true, Some(stderr)) = (compiling, env::var_os("GCCTEST_STDERR")) {
+            io::stderr()
+                .write_all(stderr.to_str().unwrap().as_bytes())
+                .unwrap();
+        }
+        if let (true, Some(code)) = (compiling, env::var_os("GCCTEST_EXIT_CODE")) {
+            std::process::exit(code.to_str().unwrap().parse().unwrap());
+        }
         break;
     }
 }
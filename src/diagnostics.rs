@@ -0,0 +1,439 @@
+//! Parsing of the diagnostics printed by compilers, so that they can be
+//! reported to Cargo as one warning each rather than line by line.
+//!
+//! GCC is asked for JSON output when it supports it. Otherwise the text
+//! output of GCC and Clang (`file:line:column: warning: message [-Wflag]`)
+//! or of MSVC (`file(line): warning C4996: message`) is parsed, and the
+//! lines around the diagnostics (source excerpts, carets, include stacks)
+//! are dropped. MSVC tools print their diagnostics on standard output, so
+//! that is parsed too for them.
+//!
+//! Clang's serialized diagnostics (`--serialize-diagnostics`) are not
+//! supported: its text output is parsed like GCC's instead, which gives the
+//! same information for each diagnostic.
+
+use std::char;
+use std::path::PathBuf;
+use std::str;
+
+use {Diagnostic, Severity};
+
+/// The way a compiler prints its diagnostics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// `-fdiagnostics-format=json` of GCC, along with the text output of the
+    /// driver, which does not use it.
+    GccJson,
+    /// The text output of GCC and Clang.
+    Gnu,
+    /// The text output of MSVC tools.
+    Msvc,
+}
+
+/// Parses the standard error of a compiler, or the standard output of an
+/// MSVC tool.
+///
+/// Returns `None` if there is output but no diagnostic could be found in it,
+/// in which case it is better shown as is.
+pub fn parse(stderr: &str, format: Format) -> Option<Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    let mut unknown = false;
+    for line in stderr.lines() {
+        let line = line.trim_right();
+        if line.is_empty() {
+            continue;
+        }
+        if format == Format::GccJson && line.starts_with('[') {
+            if let Some(json) = Json::parse(line) {
+                gcc_json(&json, &mut diagnostics);
+                continue;
+            }
+        }
+        let parsed = match format {
+            Format::GccJson | Format::Gnu => gnu_line(line),
+            Format::Msvc => msvc_line(line),
+        };
+        match parsed {
+            Some(diagnostic) => diagnostics.push(diagnostic),
+            None => unknown = true,
+        }
+    }
+    if unknown && diagnostics.is_empty() {
+        None
+    } else {
+        Some(diagnostics)
+    }
+}
+
+fn severity(kind: &str) -> Severity {
+    if kind.contains("error") || kind == "ice" || kind.starts_with("sorry") {
+        Severity::Error
+    } else if kind == "warning" {
+        Severity::Warning
+    } else {
+        Severity::Note
+    }
+}
+
+/// Adds the diagnostics of a JSON array printed by GCC, including the notes
+/// attached to them.
+fn gcc_json(json: &Json, diagnostics: &mut Vec<Diagnostic>) {
+    for diagnostic in json.as_array() {
+        let caret = diagnostic
+            .get("locations")
+            .and_then(|locations| locations.as_array().first())
+            .and_then(|location| location.get("caret"));
+        let number = |key: &str| {
+            caret
+                .and_then(|caret| caret.get(key))
+                .and_then(|n| n.as_number())
+                .map(|n| n as u32)
+        };
+        let string = |key: &str| diagnostic.get(key).and_then(|s| s.as_str());
+        diagnostics.push(Diagnostic {
+            file: caret
+                .and_then(|caret| caret.get("file"))
+                .and_then(|file| file.as_str())
+                .map(PathBuf::from),
+            line: number("line"),
+            column: number("column"),
+            severity: severity(string("kind").unwrap_or("")),
+            code: string("option").map(|s| s.to_string()),
+            message: string("message").unwrap_or("").to_string(),
+        });
+        if let Some(children) = diagnostic.get("children") {
+            gcc_json(children, diagnostics);
+        }
+    }
+}
+
+/// Parses `file:line:column: severity: message [code]`, where the location
+/// may also be just `file:line`, or the name of a program.
+fn gnu_line(line: &str) -> Option<Diagnostic> {
+    let mut found = None;
+    for kind in ["fatal error", "error", "warning", "note", "remark"].iter() {
+        let separator = format!(": {}: ", kind);
+        if let Some(i) = line.find(&separator) {
+            found = Some((&line[..i], *kind, &line[i + separator.len()..]));
+            break;
+        }
+    }
+    let (location, kind, rest) = try_opt!(found);
+
+    // Split `file:line:column` from the right, as the file name may contain
+    // colons, e.g. on Windows.
+    let mut file = location;
+    let mut numbers = Vec::new();
+    while numbers.len() < 2 {
+        let i = match file.rfind(':') {
+            Some(i) => i,
+            None => break,
+        };
+        let n = &file[i + 1..];
+        if n.is_empty() || !n.chars().all(|c| c.is_digit(10)) {
+            break;
+        }
+        numbers.insert(0, try_opt!(n.parse().ok()));
+        file = &file[..i];
+    }
+
+    // A trailing `[-Wflag]`, or `[-Werror,-Wflag]` with Clang, names the
+    // warning.
+    let (message, code) = match rest.rfind(" [") {
+        Some(i) if rest.ends_with(']') && rest[i + 2..].starts_with('-') => {
+            let code = &rest[i + 2..rest.len() - 1];
+            let code = code.rsplit(',').next().unwrap_or(code);
+            (&rest[..i], Some(code.to_string()))
+        }
+        _ => (rest, None),
+    };
+
+    Some(Diagnostic {
+        // Without a line, the location is the program which printed this.
+        file: if numbers.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(file))
+        },
+        line: numbers.first().cloned(),
+        column: numbers.get(1).cloned(),
+        severity: severity(kind),
+        code: code,
+        message: message.to_string(),
+    })
+}
+
+/// Parses `file(line[,column]): severity code: message`, or
+/// `program : Command line severity code : message`.
+fn msvc_line(line: &str) -> Option<Diagnostic> {
+    let (location, rest) = match line.find("): ").or_else(|| line.find(") : ")) {
+        Some(i) => {
+            let rest = line[i + 1..].trim_left_matches(' ');
+            if !rest.starts_with(':') {
+                return None;
+            }
+            (&line[..i + 1], &rest[1..])
+        }
+        None => {
+            let i = try_opt!(line.find(" : "));
+            (&line[..i], &line[i + 3..])
+        }
+    };
+    let mut rest = rest.trim_left();
+    if rest.starts_with("Command line ") {
+        rest = &rest["Command line ".len()..];
+    }
+
+    let mut found = None;
+    for &kind in ["fatal error", "error", "warning", "note"].iter() {
+        if rest.starts_with(kind) {
+            found = Some((kind, &rest[kind.len()..]));
+            break;
+        }
+    }
+    let (kind, rest) = try_opt!(found);
+    let colon = try_opt!(rest.find(':'));
+    let (code, message) = (rest[..colon].trim(), &rest[colon + 1..]);
+
+    let (file, line, column) = match location.rfind('(') {
+        Some(i) if location.ends_with(')') => {
+            let numbers = &location[i + 1..location.len() - 1];
+            let mut numbers = numbers.split(',').map(|n| n.trim().parse().ok());
+            (
+                Some(PathBuf::from(&location[..i])),
+                numbers.next().and_then(|n| n),
+                numbers.next().and_then(|n| n),
+            )
+        }
+        _ => (None, None, None),
+    };
+
+    Some(Diagnostic {
+        file: file,
+        line: line,
+        column: column,
+        severity: severity(kind),
+        code: if code.is_empty() {
+            None
+        } else {
+            Some(code.to_string())
+        },
+        message: message.trim().to_string(),
+    })
+}
+
+/// A minimal JSON value, enough to read the diagnostics of GCC.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(s: &str) -> Option<Json> {
+        let mut parser = Parser {
+            s: s.as_bytes(),
+            pos: 0,
+        };
+        let value = try_opt!(parser.value());
+        parser.whitespace();
+        if parser.pos == s.len() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the member `key` of an object.
+    fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> &[Json] {
+        match *self {
+            Json::Array(ref values) => values,
+            _ => &[],
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn whitespace(&mut self) {
+        while self.pos < self.s.len() && b" \t\r\n".contains(&self.s[self.pos]) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.s[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.whitespace();
+        if self.eat("null") {
+            return Some(Json::Null);
+        } else if self.eat("true") {
+            return Some(Json::Bool(true));
+        } else if self.eat("false") {
+            return Some(Json::Bool(false));
+        }
+        match *try_opt!(self.s.get(self.pos)) {
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.eat("]") {
+                    return Some(Json::Array(values));
+                }
+                loop {
+                    values.push(try_opt!(self.value()));
+                    self.whitespace();
+                    if self.eat("]") {
+                        return Some(Json::Array(values));
+                    } else if !self.eat(",") {
+                        return None;
+                    }
+                }
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.whitespace();
+                if self.eat("}") {
+                    return Some(Json::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    let key = try_opt!(self.string());
+                    self.whitespace();
+                    if !self.eat(":") {
+                        return None;
+                    }
+                    members.push((key, try_opt!(self.value())));
+                    self.whitespace();
+                    if self.eat("}") {
+                        return Some(Json::Object(members));
+                    } else if !self.eat(",") {
+                        return None;
+                    }
+                }
+            }
+            _ => {
+                let start = self.pos;
+                while self.pos < self.s.len() && b"+-.0123456789eE".contains(&self.s[self.pos]) {
+                    self.pos += 1;
+                }
+                let number = try_opt!(str::from_utf8(&self.s[start..self.pos]).ok());
+                number.parse().ok().map(Json::Number)
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if !self.eat("\"") {
+            return None;
+        }
+        let mut bytes = Vec::new();
+        loop {
+            let b = *try_opt!(self.s.get(self.pos));
+            self.pos += 1;
+            match b {
+                b'"' => return String::from_utf8(bytes).ok(),
+                b'\\' => {
+                    let escape = *try_opt!(self.s.get(self.pos));
+                    self.pos += 1;
+                    let c = match escape {
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut unit = try_opt!(self.hex4());
+                            // Characters outside of the BMP are written as
+                            // surrogate pairs. A surrogate which is not part
+                            // of one is replaced, and whatever follows it is
+                            // read on its own.
+                            let escape = self.pos;
+                            if unit >= 0xd800 && unit < 0xdc00 && self.eat("\\u") {
+                                let low = try_opt!(self.hex4());
+                                if low >= 0xdc00 && low < 0xe000 {
+                                    unit = 0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00);
+                                } else {
+                                    self.pos = escape;
+                                }
+                            }
+                            char::from_u32(unit).unwrap_or('\u{fffd}')
+                        }
+                        c => c as char,
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                b => bytes.push(b),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        if self.pos + 4 > self.s.len() {
+            return None;
+        }
+        let hex = try_opt!(str::from_utf8(&self.s[self.pos..self.pos + 4]).ok());
+        self.pos += 4;
+        u32::from_str_radix(hex, 16).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    fn string(json: &str) -> String {
+        match Json::parse(json) {
+            Some(Json::String(s)) => s,
+            _ => panic!("not a string: {}", json),
+        }
+    }
+
+    #[test]
+    fn surrogates() {
+        assert_eq!(string(r#""\ud83d\ude00""#), "\u{1f600}");
+        assert_eq!(string(r#""\ud800""#), "\u{fffd}");
+        assert_eq!(string(r#""\ud800\u0041""#), "\u{fffd}A");
+        assert_eq!(string(r#""\ud800\ud83d\ude00""#), "\u{fffd}\u{1f600}");
+        assert_eq!(string(r#""\ude00x""#), "\u{fffd}x");
+    }
+}
@@ -56,20 +56,46 @@
 #![doc(html_root_url = "https://docs.rs/cc/1.0")]
 #![cfg_attr(test, deny(warnings))]
 #![allow(deprecated)]
+// Trait objects are written without `dyn`, which Rust 1.16 doesn't know.
+#![allow(unknown_lints, bare_trait_objects)]
 #![deny(missing_docs)]
 
+#[cfg(feature = "log")]
+extern crate log;
 #[cfg(feature = "parallel")]
 extern crate rayon;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs;
 use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Stdio};
+use std::mem;
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Unwraps an `Option`, returning `None` from the enclosing function if it is
+/// empty, like `?` does for `Result`.
+macro_rules! try_opt {
+    ($e:expr) => {
+        match $e {
+            Some(v) => v,
+            None => return None,
+        }
+    };
+}
+
+mod archive;
+mod diagnostics;
+mod pkg_config;
+#[cfg(feature = "testing")]
+pub mod testing;
 
 // These modules are all glue to support reading the MSVC version from
 // the registry and from COM interfaces
@@ -93,8 +119,10 @@ pub mod windows_registry;
 #[derive(Clone, Debug)]
 pub struct Build {
     include_directories: Vec<PathBuf>,
+    exported_headers: Vec<PathBuf>,
     definitions: Vec<(String, Option<String>)>,
     objects: Vec<PathBuf>,
+    archives: Vec<PathBuf>,
     flags: Vec<String>,
     flags_supported: Vec<String>,
     known_flag_support_status: Arc<Mutex<HashMap<String, bool>>>,
@@ -105,12 +133,21 @@ pub struct Build {
     cuda: bool,
     target: Option<String>,
     host: Option<String>,
+    sysroot: Option<PathBuf>,
+    sysroot_warned: Arc<AtomicBool>,
+    android_api_level: Option<u32>,
+    bitcode: Option<Bitcode>,
     out_dir: Option<PathBuf>,
     opt_level: Option<String>,
     debug: Option<bool>,
     env: Vec<(OsString, OsString)>,
     compiler: Option<PathBuf>,
     archiver: Option<PathBuf>,
+    builtin_archiver: bool,
+    reproducible: bool,
+    keep_going: bool,
+    report_json: bool,
+    remap_path_prefixes: Vec<(PathBuf, PathBuf)>,
     cargo_metadata: bool,
     pic: Option<bool>,
     use_plt: Option<bool>,
@@ -121,6 +158,11 @@ pub struct Build {
     warnings: Option<bool>,
     extra_warnings: Option<bool>,
     env_cache: Arc<Mutex<HashMap<String, Option<String>>>>,
+    diagnostics: Collected<Vec<Diagnostic>>,
+    report: Collected<Report>,
+    plan: Option<Arc<Mutex<Plan>>>,
+    log_sink: Option<LogSink>,
+    runner: RunnerRef,
 }
 
 /// Represents the types of errors that may occur while using cc-rs.
@@ -145,6 +187,26 @@ pub struct Error {
     kind: ErrorKind,
     /// More explanation of error that occurred.
     message: String,
+    /// What is known about the tool which failed, if any.
+    details: Option<Box<ErrorDetails>>,
+}
+
+/// The details of an error coming from running a tool, kept apart so that
+/// `Error` stays small.
+#[derive(Clone, Debug, Default)]
+struct ErrorDetails {
+    /// The file which was being compiled.
+    source_file: Option<PathBuf>,
+    /// The command line of the tool.
+    args: Vec<OsString>,
+    /// How the tool exited, if it ran.
+    status: Option<ExitStatus>,
+    /// What the tool wrote to its standard error, if it ran.
+    stderr: Option<String>,
+    /// The diagnostics parsed from `stderr`.
+    diagnostics: Vec<Diagnostic>,
+    /// The errors of each file which failed to compile, in keep-going mode.
+    errors: Vec<Error>,
 }
 
 impl Error {
@@ -152,7 +214,93 @@ impl Error {
         Error {
             kind: kind,
             message: message.to_owned(),
+            details: None,
+        }
+    }
+
+    /// The error of a tool which ran but did not succeed.
+    fn tool_failed(cmd: &Invocation, program: &str, status: ExitStatus, stderr: &[u8]) -> Error {
+        let mut error = Error::new(
+            ErrorKind::ToolExecError,
+            &format!(
+                "Command {:?} with args {:?} did not execute successfully (status code {}).",
+                cmd, program, status
+            ),
+        )
+        .with_command(cmd);
+        {
+            let details = error.details_mut();
+            details.status = Some(status);
+            details.stderr = Some(String::from_utf8_lossy(stderr).into_owned());
+        }
+        error
+    }
+
+    fn details_mut(&mut self) -> &mut ErrorDetails {
+        if self.details.is_none() {
+            self.details = Some(Default::default());
         }
+        self.details.as_mut().unwrap()
+    }
+
+    fn with_command(mut self, cmd: &Invocation) -> Error {
+        let mut args = vec![cmd.program.clone()];
+        args.extend(
+            cmd.response_file_args
+                .as_ref()
+                .unwrap_or(&cmd.args)
+                .iter()
+                .cloned(),
+        );
+        self.details_mut().args = args;
+        self
+    }
+
+    fn with_source_file(mut self, path: &Path) -> Error {
+        self.details_mut().source_file = Some(path.to_owned());
+        self
+    }
+
+    /// Returns the source file whose compilation failed, if the error comes
+    /// from compiling a file.
+    pub fn source_file(&self) -> Option<&Path> {
+        self.details
+            .as_ref()
+            .and_then(|d| d.source_file.as_ref())
+            .map(|p| p.as_path())
+    }
+
+    /// Returns the command line of the tool which failed, starting with the
+    /// program, or an empty slice if the error doesn't come from running one.
+    pub fn args(&self) -> &[OsString] {
+        self.details.as_ref().map_or(&[], |d| &d.args)
+    }
+
+    /// Returns the exit status of the tool which failed, if it ran.
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.details.as_ref().and_then(|d| d.status)
+    }
+
+    /// Returns what the tool which failed wrote to its standard error, if it
+    /// ran.
+    ///
+    /// This is also printed as Cargo warnings while the tool runs.
+    pub fn stderr(&self) -> Option<&str> {
+        self.details
+            .as_ref()
+            .and_then(|d| d.stderr.as_ref())
+            .map(|s| &s[..])
+    }
+
+    /// Returns the diagnostics printed by the compiler which failed.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        self.details.as_ref().map_or(&[], |d| &d.diagnostics)
+    }
+
+    /// Returns the error of each file which failed to compile, if several
+    /// did in a build with `keep_going` enabled.
+    pub fn errors(&self) -> &[Error] {
+        self.details.as_ref().map_or(&[], |d| &d.errors)
     }
 }
 
@@ -219,6 +367,15 @@ impl ToolFamily {
         }
     }
 
+    /// What the flag to select the target sysroot looks like, if the family
+    /// supports one
+    fn sysroot_flag(&self) -> Option<&'static str> {
+        match *self {
+            ToolFamily::Msvc { .. } => None,
+            ToolFamily::Gnu | ToolFamily::Clang => Some("--sysroot="),
+        }
+    }
+
     /// What the flag to request macro-expanded source output looks like
     fn expand_flag(&self) -> &'static str {
         match *self {
@@ -274,6 +431,20 @@ impl ToolFamily {
     }
 }
 
+/// How LLVM bitcode is embedded into objects compiled for Apple targets.
+///
+/// Apple has deprecated bitcode, so none is embedded by default.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Bitcode {
+    /// Don't embed any bitcode.
+    Off,
+    /// Only embed an empty bitcode marker section (`-fembed-bitcode-marker`),
+    /// which is enough for tools that check for the presence of bitcode.
+    Marker,
+    /// Embed the full bitcode of every object (`-fembed-bitcode`).
+    Full,
+}
+
 /// Represents an object.
 ///
 /// This is a source file -> object file pair.
@@ -299,8 +470,10 @@ impl Build {
     pub fn new() -> Build {
         Build {
             include_directories: Vec::new(),
+            exported_headers: Vec::new(),
             definitions: Vec::new(),
             objects: Vec::new(),
+            archives: Vec::new(),
             flags: Vec::new(),
             flags_supported: Vec::new(),
             known_flag_support_status: Arc::new(Mutex::new(HashMap::new())),
@@ -313,12 +486,21 @@ impl Build {
             cuda: false,
             target: None,
             host: None,
+            sysroot: None,
+            sysroot_warned: Arc::new(AtomicBool::new(false)),
+            android_api_level: None,
+            bitcode: None,
             out_dir: None,
             opt_level: None,
             debug: None,
             env: Vec::new(),
             compiler: None,
             archiver: None,
+            builtin_archiver: false,
+            reproducible: false,
+            keep_going: false,
+            report_json: false,
+            remap_path_prefixes: Vec::new(),
             cargo_metadata: true,
             pic: None,
             use_plt: None,
@@ -327,6 +509,11 @@ impl Build {
             extra_warnings: None,
             warnings_into_errors: false,
             env_cache: Arc::new(Mutex::new(HashMap::new())),
+            diagnostics: Collected::default(),
+            report: Collected::default(),
+            plan: None,
+            log_sink: None,
+            runner: RunnerRef(Arc::new(ProcessRunner)),
         }
     }
 
@@ -350,6 +537,77 @@ impl Build {
         self
     }
 
+    /// Add a public header, or a directory of them, to install for the crates
+    /// depending on this one.
+    ///
+    /// When the library is compiled, headers are copied to the `include`
+    /// directory of `OUT_DIR`: a file is copied directly in it, while the
+    /// contents of a directory are copied with their layout preserved. The
+    /// `cargo:include` and `cargo:root` metadata are then printed, so that
+    /// if this crate has a `links` key, dependent crates can find the headers
+    /// through `DEP_<NAME>_INCLUDE` (see `include_from_dep`).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .export_header("include")
+    ///     .export_header("src/foo_config.h")
+    ///     .compile("foo");
+    /// ```
+    pub fn export_header<P: AsRef<Path>>(&mut self, header: P) -> &mut Build {
+        self.exported_headers.push(header.as_ref().to_path_buf());
+        self
+    }
+
+    /// Add the include directories exported by the dependency whose `links`
+    /// key is `links`, typically a `-sys` crate.
+    ///
+    /// By convention such crates print `cargo:include=` with the directories
+    /// holding their headers, or `cargo:root=` with the prefix they were
+    /// installed in, which Cargo passes on as `DEP_<NAME>_INCLUDE` and
+    /// `DEP_<NAME>_ROOT`. Each directory of `DEP_<NAME>_INCLUDE` is added;
+    /// if it is not set, the `include` directory of each entry of
+    /// `DEP_<NAME>_ROOT` is added instead. Both may hold several paths, separated
+    /// like in `PATH`.
+    ///
+    /// The build script is asked to rerun when either variable changes.
+    ///
+    /// # Example
+    ///
+    /// With `openssl-sys` as a dependency:
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .include_from_dep("openssl")
+    ///     .compile("foo");
+    /// ```
+    pub fn include_from_dep(&mut self, links: &str) -> &mut Build {
+        let prefix = format!("DEP_{}", links.to_uppercase().replace("-", "_"));
+        let include_var = format!("{}_INCLUDE", prefix);
+        let root_var = format!("{}_ROOT", prefix);
+        self.print(&format!("cargo:rerun-if-env-changed={}", include_var));
+        self.print(&format!("cargo:rerun-if-env-changed={}", root_var));
+
+        let dirs = match self.getenv(&include_var) {
+            Some(include) => env::split_paths(&include).collect::<Vec<_>>(),
+            None => match self.getenv(&root_var) {
+                Some(root) => env::split_paths(&root)
+                    .map(|root| root.join("include"))
+                    .collect(),
+                None => Vec::new(),
+            },
+        };
+        for dir in dirs {
+            if !dir.as_os_str().is_empty() && !self.include_directories.contains(&dir) {
+                self.include_directories.push(dir);
+            }
+        }
+        self
+    }
+
     /// Specify a `-D` variable with an optional value.
     ///
     /// # Example
@@ -373,6 +631,114 @@ impl Build {
         self
     }
 
+    /// Add a static library whose members are merged into the output library
+    ///
+    /// `lib.exe` is given MSVC libraries as they are. For `ar`, the members
+    /// are extracted next to the output library first, those whose names are
+    /// taken by earlier members being renamed, and the built-in archiver reads
+    /// them directly.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .archive("vendor/libsdk.a")
+    ///     .compile("foo");
+    /// ```
+    pub fn archive<P: AsRef<Path>>(&mut self, archive: P) -> &mut Build {
+        self.archives.push(archive.as_ref().to_path_buf());
+        self
+    }
+
+    /// Import the compiler flags of a library installed on the system, as
+    /// described by its pkg-config `.pc` file.
+    ///
+    /// The `.pc` file of `name` and those of the packages it requires are read
+    /// directly, so the `pkg-config` binary is not needed. Their `-I` and `-D`
+    /// flags are added to the include directories and definitions of this
+    /// build, and other `Cflags` as flags. If cargo metadata is enabled, their
+    /// `Libs` are printed as `rustc-link-search` and `rustc-link-lib`
+    /// metadata.
+    ///
+    /// Packages are looked up in `PKG_CONFIG_PATH`, followed by
+    /// `PKG_CONFIG_LIBDIR` if it is set, or otherwise the usual system
+    /// directories. When cross compiling, system directories are only searched
+    /// within the sysroot, which is `PKG_CONFIG_SYSROOT_DIR` or the one of
+    /// this build. These variables can be target specific, like `CC`.
+    ///
+    /// An error is returned if the package or one of its requirements cannot
+    /// be found, so this can be used to decide between a system library and
+    /// compiling a bundled copy.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let mut build = cc::Build::new();
+    /// build.file("src/foo.c");
+    /// if build.try_import_pkg_config("zlib").is_err() {
+    ///     build.file("zlib/inflate.c").include("zlib");
+    /// }
+    /// build.compile("foo");
+    /// ```
+    pub fn try_import_pkg_config(&mut self, name: &str) -> Result<&mut Build, Error> {
+        let sysroot = self
+            .get_var("PKG_CONFIG_SYSROOT_DIR")
+            .ok()
+            .map(PathBuf::from)
+            .or_else(|| self.get_sysroot());
+        let sysroot = sysroot.as_ref().map(|p| p.as_path());
+        let search = self.pkg_config_search_path(sysroot)?;
+        let package = pkg_config::probe(name, &search, sysroot).map_err(|e| {
+            Error::new(
+                ErrorKind::IOError,
+                &format!("Failed to import pkg-config package {}: {}", name, e),
+            )
+        })?;
+
+        for dir in package.include_dirs {
+            if !self.include_directories.contains(&dir) {
+                self.include_directories.push(dir);
+            }
+        }
+        for define in package.defines {
+            if !self.definitions.contains(&define) {
+                self.definitions.push(define);
+            }
+        }
+        for flag in package.cflags {
+            if !self.flags.contains(&flag) {
+                self.flags.push(flag);
+            }
+        }
+        for dir in package.link_paths {
+            self.print(&format!("cargo:rustc-link-search=native={}", dir.display()));
+        }
+        for lib in package.libs {
+            self.print(&format!("cargo:rustc-link-lib={}", lib));
+        }
+        for framework in package.frameworks {
+            self.print(&format!("cargo:rustc-link-lib=framework={}", framework));
+        }
+        Ok(self)
+    }
+
+    /// Import the compiler flags of a library installed on the system, as
+    /// described by its pkg-config `.pc` file.
+    ///
+    /// See `try_import_pkg_config` for the details.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the package or one of the packages it requires cannot be
+    /// found or read.
+    pub fn import_pkg_config(&mut self, name: &str) -> &mut Build {
+        if let Err(e) = self.try_import_pkg_config(name) {
+            fail(&e.message);
+        }
+        self
+    }
+
     /// Add an arbitrary flag to the invocation of the compiler
     ///
     /// # Example
@@ -423,6 +789,10 @@ impl Build {
         if let Some(is_supported) = known_status.get(flag).cloned() {
             return Ok(is_supported);
         }
+        // A plan lists the flags it can't check as conditional instead.
+        if self.plan.is_some() {
+            return Ok(false);
+        }
 
         let out_dir = self.get_out_dir()?;
         let src = self.ensure_check_file()?;
@@ -436,7 +806,19 @@ impl Build {
             .host(&host)
             .debug(false)
             .cpp(self.cpp)
-            .cuda(self.cuda);
+            .cuda(self.cuda)
+            .cargo_metadata(self.cargo_metadata);
+        // The compiler is found, and checks whether tools exist are run, the
+        // same way as for this build.
+        cfg.log_sink = self.log_sink.clone();
+        cfg.runner = self.runner.clone();
+        cfg.env = self.env.clone();
+        cfg.compiler = self.compiler.clone();
+        cfg.archiver = self.archiver.clone();
+        cfg.android_api_level = self.android_api_level;
+        if let Some(sysroot) = self.get_sysroot() {
+            cfg.sysroot(sysroot);
+        }
         let mut compiler = cfg.try_get_compiler()?;
 
         // Clang uses stderr for verbose output, which yields a false positive
@@ -445,7 +827,10 @@ impl Build {
             compiler.remove_arg("-v".into());
         }
 
-        let mut cmd = compiler.to_command();
+        let mut cmd = compiler.to_invocation();
+        for var in self.env.iter() {
+            cmd.env(&var.0, &var.1);
+        }
         let is_arm = target.contains("aarch64") || target.contains("arm");
         command_add_output_file(&mut cmd, &obj, target.contains("msvc"), false, is_arm);
 
@@ -457,7 +842,8 @@ impl Build {
 
         cmd.arg(&src);
 
-        let output = cmd.output()?;
+        cmd.capture_stdout = true;
+        let output = self.runner.0.run(&mut cmd)?;
         let is_supported = output.stderr.is_empty();
 
         known_status.insert(flag.to_owned(), is_supported);
@@ -743,6 +1129,75 @@ impl Build {
         self
     }
 
+    /// Configures the sysroot used to find headers and libraries for the
+    /// target.
+    ///
+    /// This is passed to GNU and Clang compilers as `--sysroot`, so it applies
+    /// to compilation as well as to linking when a shared object is produced.
+    /// It is ignored, with a warning, by MSVC.
+    ///
+    /// If this is not called, the `SYSROOT` environment variable is consulted,
+    /// following the same target-specific lookup as `CC` (for example
+    /// `SYSROOT_aarch64-unknown-linux-gnu` or `TARGET_SYSROOT`).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .target("aarch64-unknown-linux-gnu")
+    ///     .sysroot("/usr/aarch64-linux-gnu")
+    ///     .compile("foo");
+    /// ```
+    pub fn sysroot<P: AsRef<Path>>(&mut self, sysroot: P) -> &mut Build {
+        self.sysroot = Some(sysroot.as_ref().to_owned());
+        self
+    }
+
+    /// Configures the Android API level to compile for.
+    ///
+    /// The API level selects the NDK compiler wrapper (for example
+    /// `armv7a-linux-androideabi21-clang`) and the `--target` passed to Clang.
+    ///
+    /// If this is not called, the `ANDROID_API_LEVEL` environment variable is
+    /// consulted, following the same target-specific lookup as `CC`. The
+    /// default is 21, the lowest level supported by the NDK for all
+    /// architectures.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .target("aarch64-linux-android")
+    ///     .android_api_level(24)
+    ///     .compile("foo");
+    /// ```
+    pub fn android_api_level(&mut self, level: u32) -> &mut Build {
+        self.android_api_level = Some(level);
+        self
+    }
+
+    /// Configures whether LLVM bitcode is embedded into the objects compiled
+    /// for Apple targets (macOS, iOS, tvOS, watchOS and Mac Catalyst).
+    ///
+    /// This option defaults to `Bitcode::Off` and has no effect on other
+    /// targets.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .target("aarch64-apple-ios")
+    ///     .bitcode(cc::Bitcode::Marker)
+    ///     .compile("foo");
+    /// ```
+    pub fn bitcode(&mut self, bitcode: Bitcode) -> &mut Build {
+        self.bitcode = Some(bitcode);
+        self
+    }
+
     /// Configures the optimization level of the generated object files.
     ///
     /// This option is automatically scraped from the `OPT_LEVEL` environment
@@ -801,6 +1256,82 @@ impl Build {
         self.archiver = Some(archiver.as_ref().to_owned());
         self
     }
+
+    /// Configures whether static libraries are written by cc-rs itself rather
+    /// than by an external archiver.
+    ///
+    /// The built-in archiver writes GNU archives for ELF objects, BSD archives
+    /// for Apple targets and COFF libraries for MSVC, each with a symbol table.
+    /// Its output does not depend on timestamps or on the user running the
+    /// build, and no `ar` or `lib.exe` needs to be installed.
+    ///
+    /// This option defaults to `false`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .builtin_archiver(true)
+    ///     .compile("foo");
+    /// ```
+    pub fn builtin_archiver(&mut self, builtin_archiver: bool) -> &mut Build {
+        self.builtin_archiver = builtin_archiver;
+        self
+    }
+
+    /// Configures whether the build should avoid embedding details of the
+    /// machine it runs on into the objects and archives it produces.
+    ///
+    /// When enabled, paths are rewritten according to the
+    /// `--remap-path-prefix` options rustc was given through
+    /// `CARGO_ENCODED_RUSTFLAGS` or `RUSTFLAGS`, along with those added with
+    /// `remap_path_prefix`. GNU and Clang compilers get `-ffile-prefix-map`
+    /// (or `-fdebug-prefix-map` if they do not support it), and MSVC gets
+    /// `/Brepro` and `/pathmap`. Archives are created without timestamps or
+    /// owner ids; the built-in archiver uses `SOURCE_DATE_EPOCH` as the
+    /// timestamp of its members if it is set.
+    ///
+    /// This option defaults to `false`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .reproducible(true)
+    ///     .compile("foo");
+    /// ```
+    pub fn reproducible(&mut self, reproducible: bool) -> &mut Build {
+        self.reproducible = reproducible;
+        self
+    }
+
+    /// Adds a path prefix to rewrite in reproducible builds, in addition to the
+    /// ones given to rustc with `--remap-path-prefix`.
+    ///
+    /// Paths starting with `from` are recorded as starting with `to` instead,
+    /// in debug information and in the expansion of `__FILE__`. This has no
+    /// effect unless `reproducible` is enabled.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .reproducible(true)
+    ///     .remap_path_prefix(env!("CARGO_MANIFEST_DIR"), "/foo")
+    ///     .compile("foo");
+    /// ```
+    pub fn remap_path_prefix<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> &mut Build {
+        self.remap_path_prefixes
+            .push((from.as_ref().to_owned(), to.as_ref().to_owned()));
+        self
+    }
     /// Define whether metadata should be emitted for cargo allowing it to
     /// automatically link the binary. Defaults to `true`.
     ///
@@ -816,6 +1347,63 @@ impl Build {
         self
     }
 
+    /// Sends the informational output of the build to `sink`, along with its
+    /// level.
+    ///
+    /// This output is the commands being run and their exit status, the
+    /// environment variables read and other details of the configuration. By
+    /// default it is written to standard output if `cargo_metadata` is
+    /// enabled, or sent to the `log` crate if the `log` feature of this crate
+    /// is enabled. The `cargo:` directives are not part of it.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .log_sink(|level, message| {
+    ///         if level == cc::LogLevel::Info {
+    ///             eprintln!("{}", message);
+    ///         }
+    ///     })
+    ///     .compile("foo");
+    /// ```
+    pub fn log_sink<F>(&mut self, sink: F) -> &mut Build
+    where
+        F: Fn(LogLevel, &str) + Send + Sync + 'static,
+    {
+        self.log_sink = Some(LogSink(Arc::new(sink)));
+        self
+    }
+
+    /// Sets the runner of the commands of this build, which otherwise run as
+    /// child processes.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::io;
+    /// use std::process::Output;
+    ///
+    /// struct Verbose;
+    ///
+    /// impl cc::Runner for Verbose {
+    ///     fn run(&self, cmd: &mut cc::Invocation) -> io::Result<Output> {
+    ///         eprintln!("{:?}", cmd);
+    ///         cc::ProcessRunner.run(cmd)
+    ///     }
+    /// }
+    ///
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .runner(Verbose)
+    ///     .compile("foo");
+    /// ```
+    pub fn runner<R: Runner + 'static>(&mut self, runner: R) -> &mut Build {
+        self.runner = RunnerRef(Arc::new(runner));
+        self
+    }
+
     /// Configures whether the compiler will emit position independent code.
     ///
     /// This option defaults to `false` for `windows-gnu` targets and
@@ -859,34 +1447,128 @@ impl Build {
         self
     }
 
-    /// Run the compiler, generating the file `output`
+    /// Configures whether to compile every file even after some failed.
     ///
-    /// This will return a result instead of panicing; see compile() for the complete description.
-    pub fn try_compile(&self, output: &str) -> Result<(), Error> {
-        let (lib_name, gnu_lib_name) = if output.starts_with("lib") && output.ends_with(".a") {
-            (&output[3..output.len() - 2], output.to_owned())
-        } else {
-            let mut gnu = String::with_capacity(5 + output.len());
-            gnu.push_str("lib");
-            gnu.push_str(&output);
-            gnu.push_str(".a");
-            (output, gnu)
-        };
-        let dst = self.get_out_dir()?;
-
-        let mut objects = Vec::new();
-        for file in self.files.iter() {
-            let obj = dst.join(file).with_extension("o");
-            let obj = if !obj.starts_with(&dst) {
-                dst.join(obj.file_name().ok_or_else(|| {
-                    Error::new(ErrorKind::IOError, "Getting object file details failed.")
-                })?)
-            } else {
-                obj
-            };
-
-            match obj.parent() {
-                Some(s) => fs::create_dir_all(s)?,
+    /// When enabled, the error returned if several files fail to compile lists
+    /// all of them with their diagnostics, and `Error::errors` returns the
+    /// error of each. Otherwise compilation stops at the first failure.
+    ///
+    /// This option defaults to `false`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .file("src/bar.c")
+    ///     .keep_going(true)
+    ///     .compile("foo");
+    /// ```
+    pub fn keep_going(&mut self, keep_going: bool) -> &mut Build {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Configures whether to write the report of the build as JSON.
+    ///
+    /// When enabled, or when the `CC_REPORT_JSON` environment variable is set
+    /// to `1`, the report returned by `report` is written to
+    /// `cc-report-<output>.json` in the output directory, so that the reports
+    /// of every crate can be found in the target directory of Cargo.
+    ///
+    /// This option defaults to `false`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .report_json(true)
+    ///     .compile("foo");
+    /// ```
+    pub fn report_json(&mut self, report_json: bool) -> &mut Build {
+        self.report_json = report_json;
+        self
+    }
+
+    /// Returns how long the files and the archive of the last compilation
+    /// took to build, along with the compiler used and the size of each
+    /// object.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let mut build = cc::Build::new();
+    /// build.file("src/foo.c").file("src/bar.c").compile("foo");
+    /// for object in build.report().objects() {
+    ///     println!("{}: {:?}", object.source().display(), object.time());
+    /// }
+    /// ```
+    pub fn report(&self) -> Report {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Returns the diagnostics printed by the compiler for the files of the
+    /// last compilation of this build, including those of a file which failed
+    /// to compile.
+    ///
+    /// Each diagnostic is also printed as a single Cargo warning, unless it is
+    /// a note.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let mut build = cc::Build::new();
+    /// build.file("src/foo.c");
+    /// let result = build.try_compile("foo");
+    /// for diagnostic in build.diagnostics() {
+    ///     if diagnostic.severity() == cc::Severity::Error {
+    ///         eprintln!("{}", diagnostic);
+    ///     }
+    /// }
+    /// result.unwrap();
+    /// ```
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.lock().unwrap().clone()
+    }
+
+    /// Returns the objects the files of this build are compiled to in `dst`,
+    /// creating their directories unless a plan is being computed.
+    fn objects(&self, dst: &Path) -> Result<Vec<Object>, Error> {
+        // Objects mirror the layout of the sources in `dst` when that keeps
+        // them inside of it and apart from each other. Otherwise, e.g. for
+        // `../a/util.c` and `../b/util.c`, they are named after a hash of the
+        // path of their source, relative to the package if it's in it so that
+        // the name doesn't depend on where the package is.
+        let manifest_dir = self.getenv("CARGO_MANIFEST_DIR").map(PathBuf::from);
+        let mirrored = self
+            .files
+            .iter()
+            .map(|file| mirrored_object_path(dst, file))
+            .collect::<Vec<_>>();
+        let mut uses = HashMap::new();
+        for obj in mirrored.iter().filter_map(|obj| obj.as_ref()) {
+            *uses.entry(obj).or_insert(0) += 1;
+        }
+
+        let mut objects = Vec::new();
+        for (file, obj) in self.files.iter().zip(&mirrored) {
+            let obj = match *obj {
+                Some(ref obj) if uses[obj] == 1 => obj.clone(),
+                _ => {
+                    let key = match manifest_dir {
+                        Some(ref dir) => file.strip_prefix(dir).unwrap_or(file),
+                        None => file,
+                    };
+                    hashed_object_path(dst, key).ok_or_else(|| {
+                        Error::new(ErrorKind::IOError, "Getting object file details failed.")
+                    })?
+                }
+            };
+
+            match obj.parent() {
+                Some(_) if self.plan.is_some() => {}
+                Some(s) => fs::create_dir_all(s)?,
                 None => {
                     return Err(Error::new(
                         ErrorKind::IOError,
@@ -897,8 +1579,47 @@ impl Build {
 
             objects.push(Object::new(file.to_path_buf(), obj));
         }
+        Ok(objects)
+    }
+
+    /// Run the compiler, generating the file `output`
+    ///
+    /// This will return a result instead of panicing; see compile() for the complete description.
+    pub fn try_compile(&self, output: &str) -> Result<(), Error> {
+        let (lib_name, gnu_lib_name) = if output.starts_with("lib") && output.ends_with(".a") {
+            (&output[3..output.len() - 2], output.to_owned())
+        } else {
+            let mut gnu = String::with_capacity(5 + output.len());
+            gnu.push_str("lib");
+            gnu.push_str(&output);
+            gnu.push_str(".a");
+            (output, gnu)
+        };
+        let dst = self.get_out_dir()?;
+
+        let objects = self.objects(&dst)?;
+        if self.plan.is_none() {
+            *self.report.lock().unwrap() = Report {
+                library: lib_name.to_string(),
+                ..Report::default()
+            };
+            self.diagnostics.lock().unwrap().clear();
+        }
         self.compile_objects(&objects)?;
+        let start = Instant::now();
         self.assemble(lib_name, &dst.join(gnu_lib_name), &objects)?;
+        if self.plan.is_none() {
+            self.report.lock().unwrap().archive_time = Some(start.elapsed());
+            if self.report_json || self.getenv("CC_REPORT_JSON").map_or(false, |v| v == "1") {
+                let path = dst.join(format!("cc-report-{}.json", lib_name));
+                write_file(&path, self.report().to_json().as_bytes()).map_err(|e| {
+                    Error::new(
+                        ErrorKind::IOError,
+                        &format!("Failed to write {}: {}", path.display(), e),
+                    )
+                })?;
+            }
+        }
 
         if self.get_target()?.contains("msvc") {
             let compiler = self.get_base_compiler()?;
@@ -924,6 +1645,20 @@ impl Build {
         self.print(&format!("cargo:rustc-link-lib=static={}", lib_name));
         self.print(&format!("cargo:rustc-link-search=native={}", dst.display()));
 
+        if !self.exported_headers.is_empty() {
+            let include = dst.join("include");
+            for header in self.exported_headers.iter().filter(|_| self.plan.is_none()) {
+                install_header(header, &include).map_err(|e| {
+                    Error::new(
+                        ErrorKind::IOError,
+                        &format!("Failed to install header {}: {}", header.display(), e),
+                    )
+                })?;
+            }
+            self.print(&format!("cargo:include={}", include.display()));
+            self.print(&format!("cargo:root={}", dst.display()));
+        }
+
         // Add specific C++ libraries, if enabled.
         if self.cpp {
             if let Some(stdlib) = self.get_cpp_link_stdlib()? {
@@ -952,6 +1687,112 @@ impl Build {
         }
     }
 
+    /// Run the compiler, generating an object file for each source file
+    ///
+    /// This will return a result instead of panicing; see compile_intermediates() for the complete
+    /// description.
+    pub fn try_compile_intermediates(&self) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+        let dst = self.get_out_dir()?;
+        let objects = self.objects(&dst)?;
+        if self.plan.is_none() {
+            *self.report.lock().unwrap() = Report::default();
+            self.diagnostics.lock().unwrap().clear();
+        }
+        self.compile_objects(&objects)?;
+        Ok(objects.into_iter().map(|obj| (obj.src, obj.dst)).collect())
+    }
+
+    /// Run the compiler, generating an object file for each source file
+    ///
+    /// Unlike `compile`, the objects are not archived into a library, and
+    /// nothing is printed for Cargo to link with, so they can be linked or
+    /// archived by other means. Returns the path of the object of each source
+    /// file, in the order the files were added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the underlying compiler commands fails. It can also
+    /// panic if it fails reading file names or creating directories.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let objects = cc::Build::new()
+    ///     .file("src/foo.c")
+    ///     .file("src/bar.c")
+    ///     .compile_intermediates();
+    /// for (src, obj) in objects {
+    ///     println!("{} -> {}", src.display(), obj.display());
+    /// }
+    /// ```
+    pub fn compile_intermediates(&self) -> Vec<(PathBuf, PathBuf)> {
+        match self.try_compile_intermediates() {
+            Ok(objects) => objects,
+            Err(e) => fail(&e.message),
+        }
+    }
+
+    /// Computes what `compile` would do, without doing it.
+    ///
+    /// This will return a result instead of panicing; see plan() for the complete description.
+    pub fn try_plan(&self, output: &str) -> Result<Plan, Error> {
+        let plan = Arc::new(Mutex::new(Plan::default()));
+        let mut build = self.clone();
+        build.plan = Some(plan.clone());
+        build.try_compile(output)?;
+        let plan = plan.lock().unwrap().clone();
+        Ok(plan)
+    }
+
+    /// Computes what `compile` would do, without doing it.
+    ///
+    /// The returned plan has the commands which would be run to compile each
+    /// file and to create the archive, and the metadata which would be printed
+    /// for Cargo. No directories, objects or response files are created, and
+    /// the commands are reported with all of their arguments. Flags whose
+    /// support would have to be checked by running the compiler are listed
+    /// apart, as `PlannedCommand::conditional_args`, except that
+    /// `-fdebug-prefix-map` is used rather than `-ffile-prefix-map` for
+    /// reproducible builds. Tools are looked up in `PATH` rather than run,
+    /// and a system Clang is assumed to support wasm32, so the only program
+    /// which may be run is `xcrun`, to find an Apple SDK if `SDKROOT` is not
+    /// set to one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `output` is not formatted correctly, or if the compiler or the
+    /// archiver can't be determined.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let plan = cc::Build::new().file("src/foo.c").plan("foo");
+    /// for command in plan.commands() {
+    ///     println!("{:?} {:?}", command.program(), command.args());
+    /// }
+    /// ```
+    pub fn plan(&self, output: &str) -> Plan {
+        match self.try_plan(output) {
+            Ok(plan) => plan,
+            Err(e) => fail(&e.message),
+        }
+    }
+
+    /// Records `cmd` instead of running it if a plan is being computed, along
+    /// with the flags it would be given if the compiler supports them, and
+    /// returns whether it did.
+    fn planned(&self, cmd: &Invocation, conditional_args: &[String]) -> bool {
+        match self.plan {
+            Some(ref plan) => {
+                let mut command = PlannedCommand::new(cmd);
+                command.conditional_args = conditional_args.iter().map(OsString::from).collect();
+                plan.lock().unwrap().commands.push(command);
+                true
+            }
+            None => false,
+        }
+    }
+
     #[cfg(feature = "parallel")]
     fn compile_objects(&self, objs: &[Object]) -> Result<(), Error> {
         use self::rayon::prelude::*;
@@ -964,30 +1805,77 @@ impl Build {
             }
         }
 
-        // Check for any errors and return the first one found.
-        objs.par_iter()
+        if !self.keep_going {
+            // Check for any errors and return the first one found.
+            return objs
+                .par_iter()
+                .with_max_len(1)
+                .map(|obj| self.compile_object(obj))
+                .collect();
+        }
+
+        let errors = objs
+            .par_iter()
             .with_max_len(1)
-            .map(|obj| self.compile_object(obj))
-            .collect()
+            .filter_map(|obj| self.compile_object(obj).err())
+            .collect();
+        compile_failures(errors, objs.len())
     }
 
     #[cfg(not(feature = "parallel"))]
     fn compile_objects(&self, objs: &[Object]) -> Result<(), Error> {
+        let mut errors = Vec::new();
         for obj in objs {
-            self.compile_object(obj)?;
+            if let Err(e) = self.compile_object(obj) {
+                if !self.keep_going {
+                    return Err(e);
+                }
+                errors.push(e);
+            }
         }
-        Ok(())
+        compile_failures(errors, objs.len())
     }
 
     fn compile_object(&self, obj: &Object) -> Result<(), Error> {
         let is_asm = obj.src.extension().and_then(|s| s.to_str()) == Some("asm");
         let target = self.get_target()?;
         let msvc = target.contains("msvc");
-        let (mut cmd, name) = if msvc && is_asm {
-            self.msvc_macro_assembler()?
+        // The flags a plan can't check without running the compiler.
+        let mut conditional_args = Vec::new();
+        let (mut cmd, name, response_file, diagnostics) = if msvc && is_asm {
+            let (cmd, name) = self.msvc_macro_assembler()?;
+            (cmd, name, None, diagnostics::Format::Msvc)
         } else {
             let compiler = self.try_get_compiler()?;
-            let mut cmd = compiler.to_command();
+            // Whether the command takes a response file, with MSVC quoting
+            // rules or with GNU ones. Neither the MSVC assemblers nor nvcc do.
+            let response_file = if self.cuda {
+                None
+            } else {
+                Some(compiler.is_like_msvc())
+            };
+            if self.plan.is_some() {
+                let known_status = self.known_flag_support_status.lock().unwrap();
+                conditional_args.extend(
+                    self.flags_supported
+                        .iter()
+                        .filter(|flag| !known_status.contains_key(*flag))
+                        .cloned(),
+                );
+            }
+            let diagnostics = match compiler.family {
+                ToolFamily::Msvc { clang_cl: false } => diagnostics::Format::Msvc,
+                ToolFamily::Gnu if !self.cuda => match self.use_gcc_json_diagnostics(&compiler) {
+                    Some(true) => diagnostics::Format::GccJson,
+                    Some(false) => diagnostics::Format::Gnu,
+                    None => {
+                        conditional_args.push(gcc_json_diagnostics_flag().to_string());
+                        diagnostics::Format::Gnu
+                    }
+                },
+                _ => diagnostics::Format::Gnu,
+            };
+            let mut cmd = compiler.to_invocation();
             for &(ref a, ref b) in self.env.iter() {
                 cmd.env(a, b);
             }
@@ -999,6 +1887,8 @@ impl Build {
                     .ok_or_else(|| Error::new(ErrorKind::IOError, "Failed to get compiler path."))?
                     .to_string_lossy()
                     .into_owned(),
+                response_file,
+                diagnostics,
             )
         };
         let is_arm = target.contains("aarch64") || target.contains("arm");
@@ -1008,15 +1898,91 @@ impl Build {
             cmd.arg(if msvc { "/c" } else { "-c" });
         }
         cmd.arg(&obj.src);
+        if diagnostics == diagnostics::Format::GccJson {
+            cmd.arg(gcc_json_diagnostics_flag());
+        }
+        if self.planned(&cmd, &conditional_args) {
+            return Ok(());
+        }
+        if let Some(msvc_quoting) = response_file {
+            command_add_response_file(&mut cmd, &obj.dst, msvc_quoting)?;
+        }
 
-        run(&mut cmd, &name)?;
+        let start = Instant::now();
+        let result = run_parsing(
+            &mut cmd,
+            &name,
+            &self.logger(),
+            &*self.runner.0,
+            Some((diagnostics, &self.diagnostics.0)),
+        );
+        let time = start.elapsed();
+        let status = match result {
+            Ok(status) => Some(status),
+            Err(ref e) => e.status(),
+        };
+        self.report.lock().unwrap().objects.push(ObjectReport {
+            source: obj.src.clone(),
+            object: obj.dst.clone(),
+            compiler: PathBuf::from(&cmd.program),
+            time: time,
+            status: status,
+            size: match result {
+                Ok(_) => fs::metadata(&obj.dst).ok().map(|m| m.len()),
+                Err(_) => None,
+            },
+        });
+        result.map_err(|e| e.with_source_file(&obj.src))?;
         Ok(())
     }
 
+    /// Whether GCC can print its diagnostics as JSON, which it does since
+    /// GCC 9, and no other format was asked for. Returns `None` if a plan is
+    /// being computed and it hasn't been checked yet.
+    fn use_gcc_json_diagnostics(&self, compiler: &Tool) -> Option<bool> {
+        let flag = gcc_json_diagnostics_flag();
+        if compiler
+            .args
+            .iter()
+            .any(|a| a.to_string_lossy().starts_with("-fdiagnostics-format"))
+        {
+            return Some(false);
+        }
+
+        let mut known_status = self.known_flag_support_status.lock().unwrap();
+        if let Some(is_supported) = known_status.get(flag).cloned() {
+            return Some(is_supported);
+        }
+        if self.plan.is_some() {
+            return None;
+        }
+        // This can't use `is_flag_supported`, as GCC prints an empty array
+        // when there are no diagnostics.
+        let is_supported = self
+            .probe_gcc_json_diagnostics(compiler, flag)
+            .unwrap_or(false);
+        known_status.insert(flag.to_owned(), is_supported);
+        Some(is_supported)
+    }
+
+    fn probe_gcc_json_diagnostics(&self, compiler: &Tool, flag: &str) -> Result<bool, Error> {
+        let out_dir = self.get_out_dir()?;
+        let src = self.ensure_check_file()?;
+        let mut cmd = compiler.to_invocation();
+        cmd.arg(flag)
+            .arg("-o")
+            .arg(out_dir.join("flag_check"))
+            .arg("-c")
+            .arg(&src);
+        cmd.capture_stdout = true;
+        let output = self.runner.0.run(&mut cmd)?;
+        Ok(output.status.success() && String::from_utf8_lossy(&output.stderr).trim() == "[]")
+    }
+
     /// This will return a result instead of panicing; see expand() for the complete description.
     pub fn try_expand(&self) -> Result<Vec<u8>, Error> {
         let compiler = self.try_get_compiler()?;
-        let mut cmd = compiler.to_command();
+        let mut cmd = compiler.to_invocation();
         for &(ref a, ref b) in self.env.iter() {
             cmd.env(a, b);
         }
@@ -1038,7 +2004,7 @@ impl Build {
             .to_string_lossy()
             .into_owned();
 
-        Ok(run_output(&mut cmd, &name)?)
+        run_output(&mut cmd, &name, &self.logger(), &*self.runner.0)
     }
 
     /// Run the compiler, returning the macro-expanded version of the input files.
@@ -1085,6 +2051,36 @@ impl Build {
         }
     }
 
+    /// Get the archiver that's in use for this configuration.
+    ///
+    /// This function will return a `Tool` which represents the archiver used
+    /// to assemble the static library. Only its path, arguments and
+    /// environment are meaningful, the `is_like_*` methods describe compilers.
+    ///
+    /// The archiver is the one explicitly set with `archiver`, otherwise taken
+    /// from the `AR` environment variables, and otherwise derived from the
+    /// target and the compiler in use: a cross compiler such as
+    /// `arm-linux-gnueabihf-gcc` gets `arm-linux-gnueabihf-ar`, Clang gets the
+    /// `llvm-ar` installed next to it, and plain `ar` is the fallback.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an error occurred while determining the archiver.
+    pub fn get_archiver(&self) -> Tool {
+        match self.try_get_archiver() {
+            Ok(tool) => tool,
+            Err(e) => fail(&e.message),
+        }
+    }
+
+    /// Get the archiver that's in use for this configuration.
+    ///
+    /// This will return a result instead of panicing; see get_archiver() for the complete description.
+    pub fn try_get_archiver(&self) -> Result<Tool, Error> {
+        let (tool, _) = self.get_ar_tool()?;
+        Ok(tool)
+    }
+
     /// Get the compiler that's in use for this configuration.
     ///
     /// This will return a result instead of panicing; see get_compiler() for the complete description.
@@ -1102,7 +2098,29 @@ impl Build {
         if use_defaults {
             self.add_default_flags(&mut cmd, &target, &opt_level)?;
         } else {
-            println!("Info: default compiler flags are disabled");
+            self.log(LogLevel::Info, "default compiler flags are disabled");
+        }
+
+        if let Some(sysroot) = self.get_sysroot() {
+            match cmd.family.sysroot_flag() {
+                Some(flag) => {
+                    let mut arg = OsString::from(flag);
+                    arg.push(&sysroot);
+                    cmd.push_cc_arg(arg);
+                }
+                None if !self.sysroot_warned.swap(true, Ordering::SeqCst) => {
+                    println!(
+                        "cargo:warning=sysroot is specified, but the {:?} compiler \
+                         does not support this option, ignored",
+                        cmd.family
+                    );
+                }
+                None => {}
+            }
+        }
+
+        if self.reproducible {
+            self.add_reproducible_flags(&mut cmd);
         }
 
         for arg in envflags {
@@ -1200,9 +2218,9 @@ impl Build {
 
                 match &opt_level[..] {
                     // Msvc uses /O1 to enable all optimizations that minimize code size.
-                    "z" | "s" | "1" => cmd.push_opt_unless_duplicate("/O1".into()),
+                    "z" | "s" | "1" => self.push_opt_unless_duplicate(cmd, "/O1".into()),
                     // -O3 is a valid value for gcc and clang compilers, but not msvc. Cap to /O2.
-                    "2" | "3" => cmd.push_opt_unless_duplicate("/O2".into()),
+                    "2" | "3" => self.push_opt_unless_duplicate(cmd, "/O2".into()),
                     _ => {}
                 }
             }
@@ -1210,16 +2228,19 @@ impl Build {
                 // arm-linux-androideabi-gcc 4.8 shipped with Android NDK does
                 // not support '-Oz'
                 if opt_level == "z" && cmd.family != ToolFamily::Clang {
-                    cmd.push_opt_unless_duplicate("-Os".into());
+                    self.push_opt_unless_duplicate(cmd, "-Os".into());
                 } else {
-                    cmd.push_opt_unless_duplicate(format!("-O{}", opt_level).into());
+                    self.push_opt_unless_duplicate(cmd, format!("-O{}", opt_level).into());
                 }
 
                 if !target.contains("-ios") {
                     cmd.push_cc_arg("-ffunction-sections".into());
                     cmd.push_cc_arg("-fdata-sections".into());
                 }
-                if self.pic.unwrap_or(!target.contains("windows-gnu")) {
+                // There is no dynamic linking, and so no need for PIC, with
+                // the standalone wasm toolchains.
+                let pic_default = !target.contains("windows-gnu") && !is_bare_wasm(target);
+                if self.pic.unwrap_or(pic_default) {
                     cmd.push_cc_arg("-fPIC".into());
                     // PLT only applies if code is compiled with PIC support,
                     // and only for ELF targets.
@@ -1242,7 +2263,29 @@ impl Build {
         // Target flags
         match cmd.family {
             ToolFamily::Clang => {
-                cmd.args.push(format!("--target={}", target).into());
+                if target.contains("apple") {
+                    // The versioned target is added by `apple_flags`
+                } else if target == "wasm32-unknown-wasi" {
+                    cmd.args.push("--target=wasm32-wasi".into());
+                } else if target.starts_with("riscv") {
+                    // Clang only knows the base architecture, the extensions
+                    // are selected through `-march` instead.
+                    let arch = if target.starts_with("riscv64") {
+                        "riscv64"
+                    } else {
+                        "riscv32"
+                    };
+                    let rest = target.find('-').map_or("", |i| &target[i + 1..]);
+                    cmd.args.push(format!("--target={}-{}", arch, rest).into());
+                    self.riscv_flags(cmd, target);
+                } else if target.contains("android") {
+                    let clang_target = android_clang_target(target);
+                    let api_level = self.get_android_api_level();
+                    cmd.args
+                        .push(format!("--target={}{}", clang_target, api_level).into());
+                } else {
+                    cmd.args.push(format!("--target={}", target).into());
+                }
             }
             ToolFamily::Msvc { clang_cl } => {
                 if clang_cl {
@@ -1397,6 +2440,9 @@ impl Build {
                         cmd.args.push("-mfpu=fpv5-sp-d16".into())
                     }
                 }
+                if target.starts_with("riscv") {
+                    self.riscv_flags(cmd, target);
+                }
                 if target.starts_with("armebv7r") | target.starts_with("armv7r") {
                     if target.starts_with("armeb") {
                         cmd.args.push("-mbig-endian".into());
@@ -1425,10 +2471,10 @@ impl Build {
             }
         }
 
-        if target.contains("-ios") {
-            // FIXME: potential bug. iOS is always compiled with Clang, but Gcc compiler may be
-            // detected instead.
-            self.ios_flags(cmd)?;
+        if target.contains("apple") {
+            // FIXME: potential bug. Apple targets are always compiled with Clang, but Gcc
+            // compiler may be detected instead.
+            self.apple_flags(cmd)?;
         }
 
         if self.static_flag.unwrap_or(false) {
@@ -1457,6 +2503,53 @@ impl Build {
         Ok(())
     }
 
+    /// Adds the `-march` and `-mabi` flags for a RISC-V target, derived from
+    /// the extensions in its name (e.g. `riscv32imac`) and the enabled target
+    /// features.
+    fn riscv_flags(&self, cmd: &mut Tool, target: &str) {
+        let arch = target.split('-').next().unwrap_or("");
+        let (bits, extensions) = if arch.starts_with("riscv64") {
+            ("64", &arch[7..])
+        } else if arch.starts_with("riscv32") {
+            ("32", &arch[7..])
+        } else {
+            return;
+        };
+        let extensions = extensions.replace("g", "imafd");
+
+        // The ABI is fixed by the target, while target features may only add
+        // instructions on top of it.
+        let abi = if bits == "64" { "lp64" } else { "ilp32" };
+        let float_abi = if extensions.contains('d') {
+            "d"
+        } else if extensions.contains('f') {
+            "f"
+        } else {
+            ""
+        };
+
+        let features = self.getenv("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+        let enabled = |ext: char| {
+            extensions.contains(ext)
+                || features
+                    .split(',')
+                    .any(|feature| feature.len() == 1 && feature.starts_with(ext))
+        };
+        let mut march = format!("rv{}", bits);
+        let mut remaining = "imafdc";
+        if "imafd".chars().all(&enabled) {
+            march.push('g');
+            remaining = "c";
+        }
+        march.extend(remaining.chars().filter(|&ext| enabled(ext)));
+
+        cmd.args.push(format!("-march={}", march).into());
+        cmd.args.push(format!("-mabi={}{}", abi, float_abi).into());
+        if bits == "64" {
+            cmd.args.push("-mcmodel=medany".into());
+        }
+    }
+
     fn has_flags(&self) -> bool {
         let flags_env_var_name = if self.cpp { "CXXFLAGS" } else { "CFLAGS" };
         let flags_env_var_value = self.get_var(flags_env_var_name);
@@ -1467,7 +2560,7 @@ impl Build {
         }
     }
 
-    fn msvc_macro_assembler(&self) -> Result<(Command, String), Error> {
+    fn msvc_macro_assembler(&self) -> Result<(Invocation, String), Error> {
         let target = self.get_target()?;
         let tool = if target.contains("x86_64") {
             "ml64.exe"
@@ -1478,7 +2571,10 @@ impl Build {
         } else {
             "ml.exe"
         };
-        let mut cmd = windows_registry::find(&target, tool).unwrap_or_else(|| self.cmd(tool));
+        let mut cmd = match windows_registry::find_tool(&target, tool) {
+            Some(tool) => tool.to_invocation(),
+            None => self.cmd(tool),
+        };
         for directory in self.include_directories.iter() {
             cmd.arg("/I").arg(directory);
         }
@@ -1503,58 +2599,97 @@ impl Build {
     fn assemble(&self, lib_name: &str, dst: &Path, objs: &[Object]) -> Result<(), Error> {
         // Delete the destination if it exists as the `ar` tool at least on Unix
         // appends to it, which we don't want.
-        let _ = fs::remove_file(&dst);
+        if self.plan.is_none() {
+            let _ = fs::remove_file(dst);
+        }
 
         let objects: Vec<_> = objs.iter().map(|obj| obj.dst.clone()).collect();
         let target = self.get_target()?;
-        if target.contains("msvc") {
+        if self.builtin_archiver && self.plan.is_some() {
+            // Archives are written without running a program.
+        } else if self.builtin_archiver {
+            let format = if target.contains("msvc") {
+                archive::Format::Coff
+            } else if target.contains("apple") {
+                archive::Format::Bsd
+            } else {
+                archive::Format::Gnu
+            };
+            let members = objects
+                .iter()
+                .chain(&self.objects)
+                .map(|obj| &**obj)
+                .collect::<Vec<_>>();
+            // Members of reproducible archives get the timestamp of the
+            // build, if one was given.
+            let mtime = if self.reproducible {
+                self.getenv("SOURCE_DATE_EPOCH")
+                    .and_then(|epoch| epoch.trim().parse().ok())
+                    .unwrap_or(0)
+            } else {
+                0
+            };
+            let archives = self.archives.iter().map(|a| &**a).collect::<Vec<_>>();
+            let unreadable =
+                archive::write(dst, format, &members, &archives, mtime).map_err(|e| {
+                    Error::new(
+                        ErrorKind::IOError,
+                        &format!("Failed to write archive {}: {}", dst.display(), e),
+                    )
+                })?;
+            for member in unreadable {
+                println!(
+                    "cargo:warning=could not read the symbols of {} to index them in {}, \
+                     so the linker may not find them",
+                    member,
+                    dst.display()
+                );
+            }
+        } else if target.contains("msvc") {
             let (mut cmd, program) = self.get_ar()?;
             let mut out = OsString::from("/OUT:");
             out.push(dst);
             cmd.arg(out).arg("/nologo");
+            if self.reproducible {
+                cmd.arg("/Brepro");
+            }
 
-            // Similar to https://github.com/rust-lang/rust/pull/47507
-            // and https://github.com/rust-lang/rust/pull/48548
-            let estimated_command_line_len = objects
-                .iter()
-                .chain(&self.objects)
-                .map(|a| a.as_os_str().len())
-                .sum::<usize>();
-            if estimated_command_line_len > 1024 * 6 {
-                let mut args = String::from("\u{FEFF}"); // BOM
-                for arg in objects.iter().chain(&self.objects) {
-                    args.push('"');
-                    for c in arg.to_str().unwrap().chars() {
-                        if c == '"' {
-                            args.push('\\')
-                        }
-                        args.push(c)
-                    }
-                    args.push('"');
-                    args.push('\n');
-                }
-
-                let mut utf16le = Vec::new();
-                for code_unit in args.encode_utf16() {
-                    utf16le.push(code_unit as u8);
-                    utf16le.push((code_unit >> 8) as u8);
+            cmd.args(&objects).args(&self.objects).args(&self.archives);
+            if self.planned(&cmd, &[]) {
+                return Ok(());
+            }
+            command_add_response_file(&mut cmd, dst, true)?;
+            run(&mut cmd, &program, &self.logger(), &*self.runner.0)?;
+        } else {
+            let (mut ar, cmd) = self.get_ar()?;
+            let mut flags = "crs";
+            if self.reproducible {
+                // The `ar` of Apple's toolchain has no `D` modifier, and
+                // reads this variable instead.
+                if target.contains("apple") {
+                    ar.env("ZERO_AR_DATE", "1");
+                } else {
+                    flags = "crsD";
                 }
-
-                let mut args_file = OsString::from(dst);
-                args_file.push(".args");
-                fs::File::create(&args_file)
-                    .unwrap()
-                    .write_all(&utf16le)
-                    .unwrap();
-
-                let mut args_file_arg = OsString::from("@");
-                args_file_arg.push(args_file);
-                cmd.arg(args_file_arg);
-            } else {
-                cmd.args(&objects).args(&self.objects);
             }
-            run(&mut cmd, &program)?;
+            let members = self.extract_archives(dst)?;
+            ar.arg(flags)
+                .arg(dst)
+                .args(&objects)
+                .args(&self.objects)
+                .args(&members);
+            if self.planned(&ar, &[]) {
+                return Ok(());
+            }
+            // Apple's `ar` does not read response files, but the command line
+            // can be much longer on macOS anyway.
+            if !target.contains("apple") {
+                command_add_response_file(&mut ar, dst, false)?;
+            }
+            run(&mut ar, &cmd, &self.logger(), &*self.runner.0)?;
+        }
 
+        if target.contains("msvc") && self.plan.is_none() {
             // The Rust compiler will look for libfoo.a and foo.lib, but the
             // MSVC linker will also be passed foo.lib, so be sure that both
             // exist for now.
@@ -1572,98 +2707,273 @@ impl Build {
                     ));
                 }
             };
-        } else {
-            let (mut ar, cmd) = self.get_ar()?;
-            run(
-                ar.arg("crs").arg(dst).args(&objects).args(&self.objects),
-                &cmd,
-            )?;
         }
 
         Ok(())
     }
 
-    fn ios_flags(&self, cmd: &mut Tool) -> Result<(), Error> {
-        enum ArchSpec {
-            Device(&'static str),
-            Simulator(&'static str),
+    /// Extracts the members of the archives to merge into `dst` for `ar`,
+    /// renaming those whose names are taken, and returns their paths. They are
+    /// only read when a plan is being computed.
+    fn extract_archives(&self, dst: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut paths = Vec::new();
+        if self.archives.is_empty() {
+            return Ok(paths);
+        }
+        let dir = dst.with_extension("members");
+        if self.plan.is_none() {
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir)?;
+        }
+
+        let mut taken = HashSet::new();
+        for path in self.archives.iter() {
+            let members = archive::read(path).map_err(|e| {
+                Error::new(
+                    ErrorKind::IOError,
+                    &format!("Failed to read archive {}: {}", path.display(), e),
+                )
+            })?;
+            for (name, data) in members {
+                let name = Path::new(&name).file_name().map_or_else(
+                    || "member.o".to_string(),
+                    |n| n.to_string_lossy().into_owned(),
+                );
+                let member = dir.join(archive::unique_name(&mut taken, &name));
+                if self.plan.is_none() {
+                    write_file(&member, &data)?;
+                }
+                paths.push(member);
+            }
+        }
+        Ok(paths)
+    }
+
+    fn apple_flags(&self, cmd: &mut Tool) -> Result<(), Error> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Platform {
+            MacOs,
+            Ios,
+            TvOs,
+            WatchOs,
         }
 
         let target = self.get_target()?;
+        let platform = if target.contains("-darwin") {
+            Platform::MacOs
+        } else if target.contains("-tvos") {
+            Platform::TvOs
+        } else if target.contains("-watchos") {
+            Platform::WatchOs
+        } else {
+            Platform::Ios
+        };
+        let catalyst = target.ends_with("-macabi");
+
         let arch = target.split('-').nth(0).ok_or_else(|| {
             Error::new(
                 ErrorKind::ArchitectureInvalid,
-                "Unknown architecture for iOS target.",
+                "Unknown architecture for Apple target.",
             )
         })?;
         let arch = match arch {
-            "arm" | "armv7" | "thumbv7" => ArchSpec::Device("armv7"),
-            "armv7s" | "thumbv7s" => ArchSpec::Device("armv7s"),
-            "arm64" | "aarch64" => ArchSpec::Device("arm64"),
-            "i386" | "i686" => ArchSpec::Simulator("-m32"),
-            "x86_64" => ArchSpec::Simulator("-m64"),
+            "arm" | "armv7" | "thumbv7" => "armv7",
+            "armv7s" | "thumbv7s" => "armv7s",
+            "armv7k" => "armv7k",
+            "arm64_32" => "arm64_32",
+            "arm64" | "aarch64" => "arm64",
+            "arm64e" => "arm64e",
+            "i386" | "i686" => "i386",
+            "x86_64" => "x86_64",
             _ => {
                 return Err(Error::new(
                     ErrorKind::ArchitectureInvalid,
-                    "Unknown architecture for iOS target.",
+                    "Unknown architecture for Apple target.",
                 ));
             }
         };
-
-        let sdk = match arch {
-            ArchSpec::Device(arch) => {
-                cmd.args.push("-arch".into());
-                cmd.args.push(arch.into());
-                cmd.args.push("-miphoneos-version-min=7.0".into());
-                "iphoneos"
+        // x86 is only ever a simulator on the mobile platforms, while arm64
+        // simulators are spelled out in the target name.
+        let simulator = platform != Platform::MacOs
+            && !catalyst
+            && (target.ends_with("-sim") || arch == "i386" || arch == "x86_64");
+
+        // Use the same deployment target as rustc, which reads these variables
+        // and otherwise falls back to its own defaults.
+        let (deployment_target_var, default_version) = match platform {
+            Platform::MacOs if arch == "arm64" => ("MACOSX_DEPLOYMENT_TARGET", "11.0"),
+            Platform::MacOs => ("MACOSX_DEPLOYMENT_TARGET", "10.7"),
+            Platform::Ios if catalyst && arch == "arm64" => ("IPHONEOS_DEPLOYMENT_TARGET", "14.0"),
+            Platform::Ios if catalyst => ("IPHONEOS_DEPLOYMENT_TARGET", "13.1"),
+            Platform::Ios if simulator && arch == "arm64" => ("IPHONEOS_DEPLOYMENT_TARGET", "14.0"),
+            Platform::Ios => ("IPHONEOS_DEPLOYMENT_TARGET", "7.0"),
+            Platform::TvOs => ("TVOS_DEPLOYMENT_TARGET", "7.0"),
+            Platform::WatchOs if simulator && arch == "arm64" => {
+                ("WATCHOS_DEPLOYMENT_TARGET", "7.0")
             }
-            ArchSpec::Simulator(arch) => {
+            Platform::WatchOs => ("WATCHOS_DEPLOYMENT_TARGET", "5.0"),
+        };
+        let version = self
+            .getenv(deployment_target_var)
+            .unwrap_or_else(|| default_version.to_string());
+
+        if catalyst {
+            cmd.args
+                .push(format!("--target={}-apple-ios{}-macabi", arch, version).into());
+        } else if cmd.family == ToolFamily::Clang {
+            let os = match platform {
+                Platform::MacOs => "macosx",
+                Platform::Ios => "ios",
+                Platform::TvOs => "tvos",
+                Platform::WatchOs => "watchos",
+            };
+            let environment = if simulator { "-simulator" } else { "" };
+            cmd.args
+                .push(format!("--target={}-apple-{}{}{}", arch, os, version, environment).into());
+        } else {
+            // The native compiler builds for the host's architecture, so only
+            // pass `-arch` when that's not what we want.
+            if platform != Platform::MacOs || self.get_host()? != target {
+                cmd.args.push("-arch".into());
                 cmd.args.push(arch.into());
-                cmd.args.push("-mios-simulator-version-min=7.0".into());
-                "iphonesimulator"
             }
-        };
+            let version_min = match (platform, simulator) {
+                (Platform::MacOs, _) => "-mmacosx-version-min",
+                (Platform::Ios, false) => "-miphoneos-version-min",
+                (Platform::Ios, true) => "-mios-simulator-version-min",
+                (Platform::TvOs, false) => "-mappletvos-version-min",
+                (Platform::TvOs, true) => "-mappletvsimulator-version-min",
+                (Platform::WatchOs, false) => "-mwatchos-version-min",
+                (Platform::WatchOs, true) => "-mwatchsimulator-version-min",
+            };
+            cmd.args.push(format!("{}={}", version_min, version).into());
+        }
 
-        self.print(&format!("Detecting iOS SDK path for {}", sdk));
-        let sdk_path = self
-            .cmd("xcrun")
-            .arg("--show-sdk-path")
-            .arg("--sdk")
-            .arg(sdk)
-            .stderr(Stdio::inherit())
-            .output()?
-            .stdout;
-
-        let sdk_path = match String::from_utf8(sdk_path) {
-            Ok(p) => p,
-            Err(_) => {
-                return Err(Error::new(
-                    ErrorKind::IOError,
-                    "Unable to determine iOS SDK path.",
-                ));
-            }
-        };
+        match self.bitcode.unwrap_or(Bitcode::Off) {
+            Bitcode::Off => {}
+            Bitcode::Marker => cmd.args.push("-fembed-bitcode-marker".into()),
+            Bitcode::Full => cmd.args.push("-fembed-bitcode".into()),
+        }
 
-        cmd.args.push("-isysroot".into());
-        cmd.args.push(sdk_path.trim().into());
-        cmd.args.push("-fembed-bitcode".into());
-        /*
-         * TODO we probably ultimatedly want the -fembed-bitcode-marker flag
-         * but can't have it now because of an issue in LLVM:
-         * https://github.com/alexcrichton/cc-rs/issues/301
-         * https://github.com/rust-lang/rust/pull/48896#comment-372192660
-         */
-        /*
-        if self.get_opt_level()? == "0" {
-            cmd.args.push("-fembed-bitcode-marker".into());
-        }
-        */
+        let sdk = match (platform, simulator) {
+            (Platform::MacOs, _) => "macosx",
+            _ if catalyst => "macosx",
+            (Platform::Ios, false) => "iphoneos",
+            (Platform::Ios, true) => "iphonesimulator",
+            (Platform::TvOs, false) => "appletvos",
+            (Platform::TvOs, true) => "appletvsimulator",
+            (Platform::WatchOs, false) => "watchos",
+            (Platform::WatchOs, true) => "watchsimulator",
+        };
+
+        // The native compiler finds the macOS SDK by itself, and a cross
+        // compiler (e.g. from osxcross) usually knows where its copy is, so
+        // only pass one along when we can find it.
+        if platform == Platform::MacOs {
+            if self.get_host()?.contains("apple") && self.apple_sdkroot_var(sdk).is_none() {
+                return Ok(());
+            }
+            match self.apple_sdk_root(sdk) {
+                Ok(sdk_path) => {
+                    cmd.args.push("-isysroot".into());
+                    cmd.args.push(sdk_path);
+                }
+                Err(Error {
+                    kind: ErrorKind::ToolNotFound,
+                    ..
+                }) => {}
+                Err(e) => return Err(e),
+            }
+            return Ok(());
+        }
+
+        let sdk_path = self.apple_sdk_root(sdk)?;
+        cmd.args.push("-isysroot".into());
+        cmd.args.push(sdk_path);
 
         Ok(())
     }
 
-    fn cmd<P: AsRef<OsStr>>(&self, prog: P) -> Command {
-        let mut cmd = Command::new(prog);
+    /// Returns the path to the given Apple SDK, e.g. `iphoneos`.
+    ///
+    /// This is taken from `SDKROOT` if it's set (which also allows cross
+    /// compiling without Xcode), and otherwise asked from `xcrun`.
+    fn apple_sdk_root(&self, sdk: &str) -> Result<OsString, Error> {
+        if let Some(sdk_path) = self.apple_sdkroot_var(sdk) {
+            return Ok(sdk_path.into());
+        }
+
+        self.log(LogLevel::Info, &format!("Detecting {} SDK path", sdk));
+        let mut cmd = self.cmd("xcrun");
+        cmd.arg("--show-sdk-path").arg("--sdk").arg(sdk);
+        cmd.capture_stdout = true;
+        let output = match self.runner.0.run(&mut cmd) {
+            Ok(output) => output,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::new(
+                    ErrorKind::ToolNotFound,
+                    &format!(
+                        "Unable to find the {} SDK. Set the SDKROOT environment variable \
+                         to its path, or install Xcode so that `xcrun` can locate it.",
+                        sdk
+                    ),
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let sdk_path = String::from_utf8(output.stdout).unwrap_or_default();
+        if !output.status.success() || sdk_path.trim().is_empty() {
+            return Err(Error::new(
+                ErrorKind::IOError,
+                &format!("Unable to determine {} SDK path.", sdk),
+            ));
+        }
+
+        Ok(sdk_path.trim().into())
+    }
+
+    /// Returns `SDKROOT` if it's set to a path usable as the given SDK.
+    ///
+    /// Like rustc, this ignores an `SDKROOT` of `/` or `/usr`, and one naming
+    /// the SDK of another platform, as Xcode sets it to the SDK of the target
+    /// being built, not necessarily the one cc is compiling for.
+    fn apple_sdkroot_var(&self, sdk: &str) -> Option<String> {
+        let sdk_path = try_opt!(self.get_var("SDKROOT").ok());
+        let path = Path::new(&sdk_path).to_path_buf();
+        if path == Path::new("/") || path == Path::new("/usr") {
+            self.log(LogLevel::Info, &format!("Ignoring SDKROOT={}", sdk_path));
+            return None;
+        }
+        for component in path.components() {
+            let name = match component.as_os_str().to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            let name = if name.ends_with(".platform") {
+                &name[..name.len() - ".platform".len()]
+            } else if name.ends_with(".sdk") {
+                &name[..name.len() - ".sdk".len()]
+            } else {
+                continue;
+            };
+            // Versioned SDKs are named e.g. `iPhoneOS16.4.sdk`.
+            let name = name.trim_right_matches(|c: char| c.is_digit(10) || c == '.');
+            if let Some(other) = apple_sdk_name(name) {
+                if other != sdk {
+                    self.log(
+                        LogLevel::Info,
+                        &format!("Ignoring SDKROOT={}, which isn't the {} SDK", sdk_path, sdk),
+                    );
+                    return None;
+                }
+            }
+        }
+        Some(sdk_path)
+    }
+
+    fn cmd<P: AsRef<OsStr>>(&self, prog: P) -> Invocation {
+        let mut cmd = Invocation::new(prog);
         for &(ref a, ref b) in self.env.iter() {
             cmd.env(a, b);
         }
@@ -1723,6 +3033,36 @@ impl Build {
                     None
                 }
             })
+            .or_else(|| {
+                // NDK r19+ ships API level suffixed Clang wrappers, such as
+                // `armv7a-linux-androideabi21-clang`, and no GCC at all.
+                if target.contains("android") {
+                    self.android_ndk_bin_dir().map(|bin| {
+                        let script = if host.contains("windows") { ".cmd" } else { "" };
+                        Tool::new(bin.join(format!(
+                            "{}{}-{}{}",
+                            android_clang_target(&target),
+                            self.get_android_api_level(),
+                            clang,
+                            script
+                        )))
+                    })
+                } else {
+                    None
+                }
+            })
+            .or_else(|| {
+                // The WASI SDK ships a Clang with wasm32 support along with
+                // its sysroot and `llvm-ar`.
+                if is_bare_wasm(&target) {
+                    self.wasi_sdk_bin_dir().map(|bin| {
+                        let exe = if host.contains("windows") { ".exe" } else { "" };
+                        Tool::new(bin.join(format!("{}{}", clang, exe)))
+                    })
+                } else {
+                    None
+                }
+            })
             .or_else(|| cl_exe.clone());
 
         let tool = match tool_opt {
@@ -1735,6 +3075,12 @@ impl Build {
                         format!("{}.exe", gnu)
                     }
                 } else if target.contains("android") {
+                    let ndk_compiler = format!(
+                        "{}{}-{}",
+                        android_clang_target(&target),
+                        self.get_android_api_level(),
+                        clang
+                    );
                     let target = target
                         .replace("armv7neon", "arm")
                         .replace("armv7", "arm")
@@ -1742,19 +3088,30 @@ impl Build {
                         .replace("thumbv7", "arm");
                     let gnu_compiler = format!("{}-{}", target, gnu);
                     let clang_compiler = format!("{}-{}", target, clang);
-                    // Check if gnu compiler is present
-                    // if not, use clang
-                    if Command::new(&gnu_compiler).spawn().is_ok() {
+                    // Check for a legacy standalone toolchain with GCC, then
+                    // for the NDK's Clang wrappers in `PATH`, and finally fall
+                    // back to a standalone toolchain's Clang.
+                    if self.tool_exists(&gnu_compiler) {
                         gnu_compiler
+                    } else if self.tool_exists(&ndk_compiler) {
+                        ndk_compiler
                     } else {
                         clang_compiler
                     }
                 } else if target.contains("cloudabi") {
                     format!("{}-{}", target, traditional)
-                } else if target == "wasm32-wasi" ||
-                          target == "wasm32-unknown-wasi" ||
-                          target == "wasm32-unknown-unknown" {
-                    "clang".to_string()
+                } else if is_bare_wasm(&target) {
+                    if !self.clang_supports_wasm32(clang) {
+                        return Err(Error::new(
+                            ErrorKind::ToolNotFound,
+                            &format!(
+                                "{} does not support wasm32, set WASI_SDK_PATH to a WASI SDK \
+                                 or CC to a Clang which does",
+                                clang
+                            ),
+                        ));
+                    }
+                    clang.to_string()
                 } else if self.get_host()? != target {
                     // CROSS_COMPILE is of the form: "arm-linux-gnueabi-"
                     let cc_env = self.getenv("CROSS_COMPILE");
@@ -1800,6 +3157,12 @@ impl Build {
                         "powerpc-unknown-netbsd" => Some("powerpc--netbsd"),
                         "powerpc64-unknown-linux-gnu" => Some("powerpc-linux-gnu"),
                         "powerpc64le-unknown-linux-gnu" => Some("powerpc64le-linux-gnu"),
+                        "riscv32i-unknown-none-elf" => Some("riscv32-unknown-elf"),
+                        "riscv32imc-unknown-none-elf" => Some("riscv32-unknown-elf"),
+                        "riscv32imac-unknown-none-elf" => Some("riscv32-unknown-elf"),
+                        "riscv64gc-unknown-none-elf" => Some("riscv64-unknown-elf"),
+                        "riscv64imac-unknown-none-elf" => Some("riscv64-unknown-elf"),
+                        "riscv64gc-unknown-linux-gnu" => Some("riscv64-linux-gnu"),
                         "s390x-unknown-linux-gnu" => Some("s390x-linux-gnu"),
                         "sparc-unknown-linux-gnu" => Some("sparc-linux-gnu"),
                         "sparc64-unknown-linux-gnu" => Some("sparc64-linux-gnu"),
@@ -1998,35 +3361,158 @@ impl Build {
         }
     }
 
-    fn get_ar(&self) -> Result<(Command, String), Error> {
+    fn get_ar(&self) -> Result<(Invocation, String), Error> {
+        let (tool, name) = self.get_ar_tool()?;
+        let mut cmd = tool.to_invocation();
+        cmd.env.extend(self.env.iter().cloned());
+        Ok((cmd, name))
+    }
+
+    /// Returns the archiver along with its name for diagnostics.
+    fn get_ar_tool(&self) -> Result<(Tool, String), Error> {
         if let Some(ref p) = self.archiver {
             let name = p.file_name().and_then(|s| s.to_str()).unwrap_or("ar");
-            return Ok((self.cmd(p), name.to_string()));
+            return Ok((Tool::new(p.clone()), name.to_string()));
         }
         if let Ok(p) = self.get_var("AR") {
-            return Ok((self.cmd(&p), p));
+            return Ok((Tool::new(PathBuf::from(&p)), p));
         }
         let target = self.get_target()?;
+        let exe = if self.get_host()?.contains("windows") {
+            ".exe"
+        } else {
+            ""
+        };
         let program = if target.contains("android") {
-            format!("{}-ar", target.replace("armv7", "arm"))
+            // The NDK only ships `llvm-ar`, next to its Clang wrappers.
+            if let Some(bin) = self.android_ndk_bin_dir() {
+                let ar = bin.join(format!("llvm-ar{}", exe));
+                return Ok((Tool::new(ar), "llvm-ar".to_string()));
+            }
+            "llvm-ar".to_string()
+        } else if is_bare_wasm(&target) {
+            // The system `ar` usually can't index wasm objects.
+            if let Some(bin) = self.wasi_sdk_bin_dir() {
+                let ar = bin.join(format!("llvm-ar{}", exe));
+                return Ok((Tool::new(ar), "llvm-ar".to_string()));
+            }
+            "llvm-ar".to_string()
         } else if target.contains("emscripten") {
             // Windows use bat files so we have to be a bit more specific
             if cfg!(windows) {
-                let mut cmd = self.cmd("cmd");
-                cmd.arg("/c").arg("emar.bat");
-                return Ok((cmd, "emar.bat".to_string()));
+                let mut t = Tool::new(PathBuf::from("cmd"));
+                t.args.push("/c".into());
+                t.args.push("emar.bat".into());
+                return Ok((t, "emar.bat".to_string()));
             }
 
             "emar".to_string()
         } else if target.contains("msvc") {
-            match windows_registry::find(&target, "lib.exe") {
+            match windows_registry::find_tool(&target, "lib.exe") {
                 Some(t) => return Ok((t, "lib.exe".to_string())),
                 None => "lib.exe".to_string(),
             }
         } else {
+            // Use the archiver of the toolchain the compiler belongs to, as
+            // the host's `ar` may write an index the linker can't read.
+            // If the compiler can't be determined, `ar` is still worth a try.
+            let ar = self
+                .get_base_compiler()
+                .ok()
+                .and_then(|compiler| self.ar_for_compiler(&compiler.path));
+            if let Some(ar) = ar {
+                let name = ar
+                    .file_name()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or("ar")
+                    .to_string();
+                return Ok((Tool::new(ar), name));
+            }
             "ar".to_string()
         };
-        Ok((self.cmd(&program), program))
+        Ok((Tool::new(PathBuf::from(&program)), program))
+    }
+
+    /// Finds the archiver belonging to the toolchain of `compiler`: for
+    /// example `arm-linux-gnueabihf-ar` (or `-gcc-ar`) for
+    /// `arm-linux-gnueabihf-gcc`, or the `llvm-ar` installed next to `clang`.
+    fn ar_for_compiler(&self, compiler: &Path) -> Option<PathBuf> {
+        let exe = env::consts::EXE_SUFFIX;
+        let name = try_opt!(compiler.file_name().and_then(|name| name.to_str()));
+        let name = if name.ends_with(exe) {
+            &name[..name.len() - exe.len()]
+        } else {
+            name
+        };
+        let dir = match compiler.parent() {
+            Some(dir) if dir != Path::new("") => Some(dir),
+            _ => None,
+        };
+        let (prefix, driver) = match name.rfind('-') {
+            Some(i) => (&name[..i + 1], &name[i + 1..]),
+            None => ("", name),
+        };
+
+        // Look next to the compiler, wherever it was found in `PATH`. A plain
+        // `llvm-ar` only belongs to the compiler if it is in the same LLVM
+        // installation, which `clang` is often a link into.
+        let path = match dir {
+            Some(dir) => dir.join(try_opt!(compiler.file_name())),
+            None => try_opt!(self.which(compiler)),
+        };
+        let dir = try_opt!(path.parent()).to_path_buf();
+        let llvm_dir = match fs::canonicalize(&path) {
+            Ok(real) => try_opt!(real.parent()).to_path_buf(),
+            Err(_) => dir.clone(),
+        };
+
+        let mut candidates = Vec::new();
+        match driver {
+            "gcc" | "g++" | "cc" | "c++" if !prefix.is_empty() => {
+                candidates.push(dir.join(format!("{}ar", prefix)));
+                candidates.push(dir.join(format!("{}gcc-ar", prefix)));
+            }
+            "clang" | "clang++" => {
+                if !prefix.is_empty() {
+                    candidates.push(dir.join(format!("{}ar", prefix)));
+                }
+                candidates.push(llvm_dir.join("llvm-ar"));
+            }
+            // A versioned Clang, e.g. `clang-14`, comes with `llvm-ar-14`
+            version if prefix == "clang-" => {
+                candidates.push(dir.join(format!("llvm-ar-{}", version)));
+                candidates.push(llvm_dir.join("llvm-ar"));
+            }
+            _ => {}
+        }
+
+        candidates
+            .into_iter()
+            .map(|candidate| {
+                let mut candidate = candidate.into_os_string();
+                candidate.push(exe);
+                PathBuf::from(candidate)
+            })
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Looks up `tool` in `PATH`, as it would be when spawned.
+    fn which(&self, tool: &Path) -> Option<PathBuf> {
+        let path = self
+            .env
+            .iter()
+            .rev()
+            .find(|var| var.0 == OsStr::new("PATH"))
+            .map(|var| var.1.clone())
+            .or_else(|| env::var_os("PATH"));
+        let path = try_opt!(path);
+        let mut name = tool.as_os_str().to_owned();
+        if tool.extension().is_none() {
+            name.push(env::consts::EXE_SUFFIX);
+        }
+        env::split_paths(&path)
+            .map(|dir| dir.join(&name))
+            .find(|candidate| candidate.is_file())
     }
 
     fn get_target(&self) -> Result<String, Error> {
@@ -2043,6 +3529,234 @@ impl Build {
         }
     }
 
+    fn add_reproducible_flags(&self, cmd: &mut Tool) {
+        let mappings = self.get_remap_path_prefixes();
+        let map_arg = |flag: &str, mapping: &(PathBuf, PathBuf)| {
+            let mut arg = OsString::from(flag);
+            arg.push(&mapping.0);
+            arg.push("=");
+            arg.push(&mapping.1);
+            arg
+        };
+
+        match cmd.family {
+            ToolFamily::Msvc { clang_cl } => {
+                cmd.push_cc_arg("/Brepro".into());
+                for mapping in mappings.iter() {
+                    let arg = if clang_cl {
+                        map_arg("/clang:-ffile-prefix-map=", mapping)
+                    } else {
+                        map_arg("/pathmap:", mapping)
+                    };
+                    cmd.push_cc_arg(arg);
+                }
+            }
+            ToolFamily::Gnu | ToolFamily::Clang => {
+                if mappings.is_empty() {
+                    return;
+                }
+                // `-ffile-prefix-map` also covers `__FILE__`, but it is only
+                // available since GCC 8 and Clang 10. A failed check is cached
+                // too, so that it runs once rather than for every object.
+                let probe = "-ffile-prefix-map=a=b";
+                let supported = self.is_flag_supported(probe).unwrap_or_else(|_| {
+                    self.known_flag_support_status
+                        .lock()
+                        .unwrap()
+                        .insert(probe.to_string(), false);
+                    false
+                });
+                let flag = if supported {
+                    "-ffile-prefix-map="
+                } else {
+                    "-fdebug-prefix-map="
+                };
+                for mapping in mappings.iter() {
+                    cmd.push_cc_arg(map_arg(flag, mapping));
+                }
+            }
+        }
+    }
+
+    /// Returns the path prefixes to remap, as given to rustc and then to this
+    /// build.
+    fn get_remap_path_prefixes(&self) -> Vec<(PathBuf, PathBuf)> {
+        let rustflags = match self.getenv("CARGO_ENCODED_RUSTFLAGS") {
+            Some(flags) => flags
+                .split('\u{1f}')
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>(),
+            None => self
+                .getenv("RUSTFLAGS")
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect(),
+        };
+
+        let prefix = "--remap-path-prefix=";
+        let mut mappings = Vec::new();
+        let mut flags = rustflags.iter();
+        while let Some(flag) = flags.next() {
+            let mapping = if flag == "--remap-path-prefix" {
+                flags.next().map(|s| &s[..])
+            } else if flag.starts_with(prefix) {
+                Some(&flag[prefix.len()..])
+            } else {
+                None
+            };
+            // Like rustc, split on the last `=` since the source prefix is the
+            // one more likely to contain one.
+            if let Some(mapping) = mapping {
+                if let Some(i) = mapping.rfind('=') {
+                    let (from, to) = (&mapping[..i], &mapping[i + 1..]);
+                    mappings.push((PathBuf::from(from), PathBuf::from(to)));
+                }
+            }
+        }
+        mappings.extend(self.remap_path_prefixes.iter().cloned());
+        mappings
+    }
+
+    fn pkg_config_search_path(&self, sysroot: Option<&Path>) -> Result<Vec<PathBuf>, Error> {
+        let mut search = Vec::new();
+        if let Ok(path) = self.get_var("PKG_CONFIG_PATH") {
+            search.extend(env::split_paths(&path).filter(|p| !p.as_os_str().is_empty()));
+        }
+        if let Ok(libdir) = self.get_var("PKG_CONFIG_LIBDIR") {
+            search.extend(env::split_paths(&libdir).filter(|p| !p.as_os_str().is_empty()));
+            return Ok(search);
+        }
+
+        // The system directories of the host are of no use for another target.
+        let target = self.get_target()?;
+        if sysroot.is_none() && target != self.get_host()? {
+            return Ok(search);
+        }
+        let mut dirs = vec![
+            "/usr/local/lib/pkgconfig".to_string(),
+            "/usr/local/share/pkgconfig".to_string(),
+        ];
+        if let Some(multiarch) = multiarch_tuple(&target) {
+            dirs.push(format!("/usr/lib/{}/pkgconfig", multiarch));
+        }
+        dirs.push("/usr/lib64/pkgconfig".to_string());
+        dirs.push("/usr/lib/pkgconfig".to_string());
+        dirs.push("/usr/share/pkgconfig".to_string());
+        search.extend(dirs.iter().map(|dir| match sysroot {
+            Some(sysroot) => sysroot.join(&dir[1..]),
+            None => PathBuf::from(dir),
+        }));
+        Ok(search)
+    }
+
+    fn get_sysroot(&self) -> Option<PathBuf> {
+        match self.sysroot.clone() {
+            Some(s) => Some(s),
+            None => self.get_var("SYSROOT").ok().map(PathBuf::from).or_else(|| {
+                // Default to the sysroot of the WASI SDK, if there is one.
+                let target = try_opt!(self.get_target().ok());
+                if target == "wasm32-wasi" || target == "wasm32-unknown-wasi" {
+                    let sdk = try_opt!(self.getenv("WASI_SDK_PATH"));
+                    Some(Path::new(&sdk).join("share").join("wasi-sysroot"))
+                } else {
+                    None
+                }
+            }),
+        }
+    }
+
+    fn get_android_api_level(&self) -> u32 {
+        match self.android_api_level {
+            Some(level) => level,
+            None => self
+                .get_var("ANDROID_API_LEVEL")
+                .ok()
+                .and_then(|level| level.trim().parse().ok())
+                .unwrap_or(21),
+        }
+    }
+
+    /// Returns the `bin` directory of the NDK's LLVM toolchain, if the NDK was
+    /// located through `ANDROID_NDK_HOME` or `ANDROID_NDK_ROOT`.
+    fn android_ndk_bin_dir(&self) -> Option<PathBuf> {
+        let ndk = self
+            .getenv("ANDROID_NDK_HOME")
+            .or_else(|| self.getenv("ANDROID_NDK_ROOT"));
+        let ndk = try_opt!(ndk);
+        let host = try_opt!(self.get_host().ok());
+        let host_tag = if host.contains("apple") {
+            // The NDK only ships a (universal) x86_64 toolchain for macOS.
+            "darwin-x86_64"
+        } else if host.contains("windows") {
+            if host.contains("x86_64") {
+                "windows-x86_64"
+            } else {
+                "windows"
+            }
+        } else {
+            "linux-x86_64"
+        };
+        let bin = Path::new(&ndk)
+            .join("toolchains/llvm/prebuilt")
+            .join(host_tag)
+            .join("bin");
+        if bin.is_dir() {
+            Some(bin)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the `bin` directory of the WASI SDK, if it was located through
+    /// `WASI_SDK_PATH`.
+    fn wasi_sdk_bin_dir(&self) -> Option<PathBuf> {
+        let sdk = try_opt!(self.getenv("WASI_SDK_PATH"));
+        let bin = Path::new(&sdk).join("bin");
+        if bin.is_dir() {
+            Some(bin)
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether `clang` lists wasm32 among its targets, which it doesn't
+    /// when LLVM was built without the WebAssembly backend. The result is
+    /// cached with the flags known to be supported.
+    fn clang_supports_wasm32(&self, clang: &str) -> bool {
+        let key = format!("{} --print-targets: wasm32", clang);
+        let mut known_status = self.known_flag_support_status.lock().unwrap();
+        if let Some(is_supported) = known_status.get(&key).cloned() {
+            return is_supported;
+        }
+        if self.plan.is_some() {
+            return true;
+        }
+
+        let mut cmd = self.cmd(clang);
+        cmd.arg("--print-targets");
+        cmd.capture_stdout = true;
+        let is_supported = match self.runner.0.run(&mut cmd) {
+            Ok(ref output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.trim_left().starts_with("wasm32 ")),
+            _ => false,
+        };
+        known_status.insert(key, is_supported);
+        is_supported
+    }
+
+    /// Checks whether `tool` can be spawned, i.e. that it exists in `PATH`.
+    fn tool_exists(&self, tool: &str) -> bool {
+        if self.plan.is_some() {
+            return self.which(Path::new(tool)).is_some();
+        }
+        let mut cmd = self.cmd(tool);
+        cmd.arg("--version");
+        cmd.capture_stdout = true;
+        self.runner.0.run(&mut cmd).is_ok()
+    }
+
     fn get_opt_level(&self) -> Result<String, Error> {
         match self.opt_level.as_ref().cloned() {
             Some(ol) => Ok(ol),
@@ -2070,12 +3784,17 @@ impl Build {
     }
 
     fn getenv(&self, v: &str) -> Option<String> {
+        // Variables set on this build take precedence over the process's, and
+        // aren't cached since they can change between calls.
+        if let Some(var) = self.env.iter().rev().find(|var| var.0 == OsStr::new(v)) {
+            return var.1.to_str().map(|s| s.to_string());
+        }
         let mut cache = self.env_cache.lock().unwrap();
         if let Some(val) = cache.get(v) {
             return val.clone();
         }
         let r = env::var(v).ok();
-        self.print(&format!("{} = {:?}", v, r));
+        self.log(LogLevel::Debug, &format!("{} = {:?}", v, r));
         cache.insert(v.to_string(), r.clone());
         r
     }
@@ -2090,8 +3809,37 @@ impl Build {
         }
     }
 
+    /// Don't push optimization arg if it conflicts with existing args
+    fn push_opt_unless_duplicate(&self, cmd: &mut Tool, flag: OsString) {
+        if cmd.is_duplicate_opt_arg(&flag) {
+            self.log(
+                LogLevel::Info,
+                &format!("Ignoring duplicate arg {:?}", &flag),
+            );
+        } else {
+            cmd.push_cc_arg(flag);
+        }
+    }
+
+    fn logger(&self) -> Logger {
+        Logger {
+            sink: self.log_sink.clone(),
+            stdout: self.cargo_metadata,
+        }
+    }
+
+    fn log(&self, level: LogLevel, message: &str) {
+        self.logger().log(level, message);
+    }
+
     fn print(&self, s: &str) {
         if self.cargo_metadata {
+            if let Some(ref plan) = self.plan {
+                if s.starts_with("cargo:") {
+                    plan.lock().unwrap().metadata.push(s.to_string());
+                    return;
+                }
+            }
             println!("{}", s);
         }
     }
@@ -2183,28 +3931,23 @@ impl Tool {
         return false;
     }
 
-    /// Don't push optimization arg if it conflicts with existing args
-    fn push_opt_unless_duplicate(&mut self, flag: OsString) {
-        if self.is_duplicate_opt_arg(&flag) {
-            println!("Info: Ignoring duplicate arg {:?}", &flag);
-        } else {
-            self.push_cc_arg(flag);
-        }
-    }
-
     /// Converts this compiler into a `Command` that's ready to be run.
     ///
     /// This is useful for when the compiler needs to be executed and the
     /// command returned will already have the initial arguments and environment
     /// variables configured.
     pub fn to_command(&self) -> Command {
+        self.to_invocation().to_command()
+    }
+
+    fn to_invocation(&self) -> Invocation {
         let mut cmd = match self.cc_wrapper_path {
             Some(ref cc_wrapper_path) => {
-                let mut cmd = Command::new(&cc_wrapper_path);
+                let mut cmd = Invocation::new(cc_wrapper_path);
                 cmd.arg(&self.path);
                 cmd
             }
-            None => Command::new(&self.path),
+            None => Invocation::new(&self.path),
         };
         cmd.args(&self.cc_wrapper_args);
 
@@ -2296,92 +4039,679 @@ impl Tool {
     }
 }
 
-fn run(cmd: &mut Command, program: &str) -> Result<(), Error> {
-    let (mut child, print) = spawn(cmd, program)?;
-    let status = match child.wait() {
-        Ok(s) => s,
-        Err(_) => {
-            return Err(Error::new(
-                ErrorKind::ToolExecError,
-                &format!(
-                    "Failed to wait on spawned child process, command {:?} with args {:?}.",
-                    cmd, program
-                ),
+/// How serious a compiler diagnostic is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// An error, which makes the compilation fail.
+    Error,
+    /// A warning.
+    Warning,
+    /// A note or remark, usually attached to a previous error or warning.
+    Note,
+}
+
+/// A diagnostic printed by the compiler while compiling a file.
+///
+/// The diagnostics of a build are available from `Build::diagnostics`, and
+/// errors and warnings are printed as Cargo warnings, one line each.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    file: Option<PathBuf>,
+    line: Option<u32>,
+    column: Option<u32>,
+    severity: Severity,
+    code: Option<String>,
+    message: String,
+}
+
+impl Diagnostic {
+    /// Returns the file the diagnostic is about, if any.
+    ///
+    /// Some diagnostics are about the compiler invocation rather than the
+    /// sources, e.g. unknown options.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_ref().map(|p| p.as_path())
+    }
+
+    /// Returns the line the diagnostic is about, starting from 1.
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+
+    /// Returns the column the diagnostic is about, starting from 1.
+    pub fn column(&self) -> Option<u32> {
+        self.column
+    }
+
+    /// Returns the severity of the diagnostic.
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    /// Returns the code of the diagnostic, such as `-Wunused-variable` for
+    /// GCC and Clang or `C4996` for MSVC, if it has one.
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_ref().map(|c| &c[..])
+    }
+
+    /// Returns the message of the diagnostic.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    /// Formats the diagnostic like GCC does, e.g.
+    /// `foo.c:3:9: warning: unused variable 'x' [-Wunused-variable]`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file.display())?;
+            if let Some(line) = self.line {
+                write!(f, "{}:", line)?;
+                if let Some(column) = self.column {
+                    write!(f, "{}:", column)?;
+                }
+            }
+            write!(f, " ")?;
+        }
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        };
+        write!(f, "{}: {}", severity, self.message)?;
+        if let Some(ref code) = self.code {
+            write!(f, " [{}]", code)?;
+        }
+        Ok(())
+    }
+}
+
+/// The flag which makes GCC print its diagnostics as JSON.
+fn gcc_json_diagnostics_flag() -> &'static str {
+    "-fdiagnostics-format=json"
+}
+
+/// What `Build::compile` would do, as computed by `Build::plan`.
+#[derive(Clone, Debug, Default)]
+pub struct Plan {
+    commands: Vec<PlannedCommand>,
+    metadata: Vec<String>,
+}
+
+impl Plan {
+    /// Returns the commands which would be run, in order, except that files
+    /// compiled in parallel could be compiled in any order.
+    pub fn commands(&self) -> &[PlannedCommand] {
+        &self.commands
+    }
+
+    /// Returns the lines which would be printed for Cargo, like
+    /// `cargo:rustc-link-lib=static=foo`.
+    pub fn metadata(&self) -> &[String] {
+        &self.metadata
+    }
+}
+
+/// A command which would be run by `Build::compile`.
+#[derive(Clone, Debug)]
+pub struct PlannedCommand {
+    program: OsString,
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    conditional_args: Vec<OsString>,
+}
+
+impl PlannedCommand {
+    fn new(cmd: &Invocation) -> PlannedCommand {
+        PlannedCommand {
+            program: cmd.program.clone(),
+            args: cmd.args.clone(),
+            env: cmd.env.clone(),
+            conditional_args: Vec::new(),
+        }
+    }
+
+    /// Returns the program which would be run.
+    pub fn program(&self) -> &OsStr {
+        &self.program
+    }
+
+    /// Returns the arguments the program would be given.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// Returns the flags the program would also be given if the compiler
+    /// supports them, which can't be known without running it. These are
+    /// the flags of `Build::flag_if_supported` which haven't been checked
+    /// yet and, for GCC, `-fdiagnostics-format=json`.
+    ///
+    /// They are not part of `args`.
+    pub fn conditional_args(&self) -> &[OsString] {
+        &self.conditional_args
+    }
+
+    /// Returns the environment variables the program would be given in
+    /// addition to those of the build script.
+    pub fn env(&self) -> &[(OsString, OsString)] {
+        &self.env
+    }
+
+    /// Returns a command which runs the program like `Build::compile` would.
+    pub fn to_command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args);
+        for var in self.env.iter() {
+            cmd.env(&var.0, &var.1);
+        }
+        cmd
+    }
+}
+
+/// How long the files and the archive of a compilation took to build.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    library: String,
+    objects: Vec<ObjectReport>,
+    archive_time: Option<Duration>,
+}
+
+impl Report {
+    /// Returns the name of the library, as given to `Build::compile`.
+    pub fn library(&self) -> &str {
+        &self.library
+    }
+
+    /// Returns the report of each file compiled, in the order they finished.
+    pub fn objects(&self) -> &[ObjectReport] {
+        &self.objects
+    }
+
+    /// Returns how long the archive took to create, if it was.
+    pub fn archive_time(&self) -> Option<Duration> {
+        self.archive_time
+    }
+
+    /// Returns the report as a JSON object, with times in seconds.
+    ///
+    /// ```json
+    /// {
+    ///   "library": "foo",
+    ///   "archive_time": 0.012,
+    ///   "objects": [
+    ///     {"source": "src/foo.c", "object": "/out/src/foo.o", "compiler": "cc",
+    ///      "time": 0.25, "status": 0, "size": 4096}
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// The status is the exit code of the compiler, which is null if it did
+    /// not run or was killed by a signal, and the size is null if the object
+    /// was not created.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\n");
+        json.push_str(&format!("  \"library\": {},\n", json_string(&self.library)));
+        json.push_str(&format!(
+            "  \"archive_time\": {},\n",
+            self.archive_time
+                .map_or("null".to_string(), |t| secs(t).to_string())
+        ));
+        json.push_str("  \"objects\": [");
+        for (i, object) in self.objects.iter().enumerate() {
+            json.push_str(if i == 0 { "\n" } else { ",\n" });
+            json.push_str(&format!(
+                "    {{\"source\": {}, \"object\": {}, \"compiler\": {}, \
+                 \"time\": {}, \"status\": {}, \"size\": {}}}",
+                json_string(&object.source.to_string_lossy()),
+                json_string(&object.object.to_string_lossy()),
+                json_string(&object.compiler.to_string_lossy()),
+                secs(object.time),
+                object
+                    .status
+                    .and_then(|s| s.code())
+                    .map_or("null".to_string(), |c| c.to_string()),
+                object.size.map_or("null".to_string(), |s| s.to_string()),
             ));
         }
-    };
-    print.join().unwrap();
-    println!("{}", status);
+        json.push_str("\n  ]\n}\n");
+        json
+    }
+}
+
+/// How long a file took to compile, and with what result.
+#[derive(Clone, Debug)]
+pub struct ObjectReport {
+    source: PathBuf,
+    object: PathBuf,
+    compiler: PathBuf,
+    time: Duration,
+    status: Option<ExitStatus>,
+    size: Option<u64>,
+}
+
+impl ObjectReport {
+    /// Returns the source file.
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Returns the object file.
+    pub fn object(&self) -> &Path {
+        &self.object
+    }
+
+    /// Returns the compiler or assembler which compiled the file.
+    pub fn compiler(&self) -> &Path {
+        &self.compiler
+    }
+
+    /// Returns the wall time the compilation took.
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    /// Returns the exit status of the compiler, if it ran.
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.status
+    }
+
+    /// Returns the size of the object file, if the compilation succeeded.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns `duration` in seconds.
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// The level of a message of the informational output, set to a sink with
+/// `Build::log_sink`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LogLevel {
+    /// What is being done, e.g. the commands being run.
+    Info,
+    /// Details of the configuration, e.g. the environment variables read.
+    Debug,
+}
+
+type LogFn = Fn(LogLevel, &str) + Send + Sync;
+
+/// A sink set with `Build::log_sink`.
+#[derive(Clone)]
+struct LogSink(Arc<LogFn>);
+
+impl fmt::Debug for LogSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("LogSink")
+    }
+}
+
+/// Where the informational output of a build goes.
+#[derive(Clone, Debug)]
+struct Logger {
+    sink: Option<LogSink>,
+    /// Whether to write to standard output if there is no sink.
+    stdout: bool,
+}
+
+impl Logger {
+    fn log(&self, level: LogLevel, message: &str) {
+        if let Some(ref sink) = self.sink {
+            (sink.0)(level, message);
+        } else if cfg!(feature = "log") {
+            #[cfg(feature = "log")]
+            match level {
+                LogLevel::Info => log::info!(target: "cc", "{}", message),
+                LogLevel::Debug => log::debug!(target: "cc", "{}", message),
+            }
+        } else if self.stdout {
+            println!("{}", message);
+        }
+    }
+}
+
+/// A command run by a `Build`, e.g. a compiler, as given to its `Runner`.
+///
+/// Unlike a `Command`, its program, arguments and environment can be read
+/// back, so that a runner can record or rewrite them.
+#[derive(Clone)]
+pub struct Invocation {
+    program: OsString,
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    capture_stdout: bool,
+    /// The arguments moved to a response file, which errors report instead
+    /// of the `@file` passed to the program.
+    response_file_args: Option<Vec<OsString>>,
+}
+
+impl Invocation {
+    /// Creates a command running `program` with no arguments.
+    pub fn new<P: AsRef<OsStr>>(program: P) -> Invocation {
+        Invocation {
+            program: program.as_ref().to_owned(),
+            args: Vec::new(),
+            env: Vec::new(),
+            capture_stdout: false,
+            response_file_args: None,
+        }
+    }
+
+    /// Adds an argument to pass to the program.
+    pub fn arg<A: AsRef<OsStr>>(&mut self, arg: A) -> &mut Invocation {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Adds multiple arguments to pass to the program.
+    pub fn args<I, A>(&mut self, args: I) -> &mut Invocation
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Sets an environment variable of the program, in addition to those of
+    /// the build script.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Invocation {
+        self.env
+            .push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        self
+    }
+
+    /// Returns the program to run.
+    pub fn get_program(&self) -> &OsStr {
+        &self.program
+    }
+
+    /// Returns the arguments passed to the program.
+    pub fn get_args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// Returns the environment variables set with `env`, in order.
+    pub fn get_envs(&self) -> &[(OsString, OsString)] {
+        &self.env
+    }
+
+    /// Whether the standard output of the program is needed, in which case
+    /// it must be returned by the runner instead of being inherited.
+    pub fn captures_stdout(&self) -> bool {
+        self.capture_stdout
+    }
 
-    if status.success() {
+    /// Returns a `Command` running the program with the same arguments and
+    /// environment.
+    pub fn to_command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args);
+        for var in self.env.iter() {
+            cmd.env(&var.0, &var.1);
+        }
+        cmd
+    }
+}
+
+impl fmt::Debug for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.program)?;
+        for arg in self.args.iter() {
+            write!(f, " {:?}", arg)?;
+        }
         Ok(())
-    } else {
-        Err(Error::new(
-            ErrorKind::ToolExecError,
-            &format!(
-                "Command {:?} with args {:?} did not execute successfully (status code {}).",
-                cmd, program, status
-            ),
-        ))
     }
 }
 
-fn run_output(cmd: &mut Command, program: &str) -> Result<Vec<u8>, Error> {
-    cmd.stdout(Stdio::piped());
-    let (mut child, print) = spawn(cmd, program)?;
-    let mut stdout = vec![];
-    child
-        .stdout
-        .take()
-        .unwrap()
-        .read_to_end(&mut stdout)
-        .unwrap();
-    let status = match child.wait() {
-        Ok(s) => s,
-        Err(_) => {
-            return Err(Error::new(
-                ErrorKind::ToolExecError,
-                &format!(
-                    "Failed to wait on spawned child process, command {:?} with args {:?}.",
-                    cmd, program
-                ),
-            ));
+/// Runs the commands of a build, i.e. the compilers, assemblers and archivers,
+/// and the compiler to check which flags it supports.
+///
+/// The default is `ProcessRunner`, and another runner can be set with
+/// `Build::runner`, e.g. to record the commands, to rewrite them, or to run
+/// them elsewhere.
+pub trait Runner: Send + Sync {
+    /// Runs `cmd` to completion.
+    ///
+    /// The returned output contains the standard error of `cmd`, and its
+    /// standard output if `cmd.captures_stdout()`.
+    fn run(&self, cmd: &mut Invocation) -> io::Result<Output>;
+
+    /// Runs `cmd` to completion like `run`, also passing each line of its
+    /// standard error to `line`, without the newline, as it is written.
+    ///
+    /// This is how the output of tools is shown while they run. The default
+    /// implementation passes the lines once `run` returns.
+    fn run_streaming(&self, cmd: &mut Invocation, line: &mut FnMut(&[u8])) -> io::Result<Output> {
+        let output = self.run(cmd)?;
+        {
+            let stderr = if output.stderr.ends_with(b"\n") {
+                &output.stderr[..output.stderr.len() - 1]
+            } else {
+                &output.stderr[..]
+            };
+            if !stderr.is_empty() {
+                for l in stderr.split(|&b| b == b'\n') {
+                    line(l);
+                }
+            }
         }
+        Ok(output)
+    }
+}
+
+/// The default `Runner`, which runs commands as child processes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProcessRunner;
+
+impl Runner for ProcessRunner {
+    fn run(&self, cmd: &mut Invocation) -> io::Result<Output> {
+        self.run_streaming(cmd, &mut |_| {})
+    }
+
+    fn run_streaming(&self, cmd: &mut Invocation, line: &mut FnMut(&[u8])) -> io::Result<Output> {
+        let mut command = cmd.to_command();
+        command.stderr(Stdio::piped());
+        if cmd.capture_stdout {
+            command.stdout(Stdio::piped());
+        }
+        let mut child = command.spawn()?;
+        // Standard output is read on another thread, so that standard error
+        // can be passed on as it is written.
+        let stdout = child.stdout.take().map(|mut stdout| {
+            thread::spawn(move || {
+                let mut output = Vec::new();
+                stdout.read_to_end(&mut output).map(|_| output)
+            })
+        });
+        let mut stderr = Vec::new();
+        if let Some(pipe) = child.stderr.take() {
+            for l in BufReader::new(pipe).split(b'\n') {
+                let l = l?;
+                line(&l);
+                stderr.extend_from_slice(&l);
+                stderr.push(b'\n');
+            }
+        }
+        let status = child.wait()?;
+        let stdout = match stdout {
+            Some(thread) => thread.join().unwrap()?,
+            None => Vec::new(),
+        };
+        Ok(Output {
+            status: status,
+            stdout: stdout,
+            stderr: stderr,
+        })
+    }
+}
+
+/// A runner set with `Build::runner`.
+#[derive(Clone)]
+struct RunnerRef(Arc<Runner>);
+
+impl fmt::Debug for RunnerRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Runner")
+    }
+}
+
+/// What a `Build` collects while it compiles. Unlike the caches, which
+/// clones of a `Build` share, each clone gets a copy of it.
+#[derive(Debug, Default)]
+struct Collected<T>(Mutex<T>);
+
+impl<T> Deref for Collected<T> {
+    type Target = Mutex<T>;
+
+    fn deref(&self) -> &Mutex<T> {
+        &self.0
+    }
+}
+
+impl<T: Clone> Clone for Collected<T> {
+    fn clone(&self) -> Collected<T> {
+        Collected(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+fn run(cmd: &mut Invocation, program: &str, log: &Logger, runner: &Runner) -> Result<(), Error> {
+    run_parsing(cmd, program, log, runner, None).map(|_| ())
+}
+
+/// Runs a compiler like `run`, also parsing its diagnostics in the given
+/// format into the given list, whether it succeeds or not.
+fn run_parsing(
+    cmd: &mut Invocation,
+    program: &str,
+    log: &Logger,
+    runner: &Runner,
+    diagnostics: Option<(diagnostics::Format, &Mutex<Vec<Diagnostic>>)>,
+) -> Result<ExitStatus, Error> {
+    let format = diagnostics.map(|(format, _)| format);
+    // MSVC tools print their diagnostics on standard output rather than on
+    // standard error.
+    let msvc = format == Some(diagnostics::Format::Msvc);
+    cmd.capture_stdout = msvc;
+    let output = execute(cmd, program, log, runner, format.is_none())?;
+    let mut parsed = match format {
+        Some(format) => print_stderr(&output.stderr, format),
+        None => Vec::new(),
     };
-    print.join().unwrap();
-    println!("{}", status);
+    if msvc {
+        parsed.extend(print_stdout(&output.stdout, diagnostics::Format::Msvc));
+    }
+    if let Some((_, diagnostics)) = diagnostics {
+        diagnostics.lock().unwrap().extend(parsed.iter().cloned());
+    }
 
-    if status.success() {
-        Ok(stdout)
+    if output.status.success() {
+        Ok(output.status)
     } else {
-        Err(Error::new(
-            ErrorKind::ToolExecError,
-            &format!(
-                "Command {:?} with args {:?} did not execute successfully (status code {}).",
-                cmd, program, status
-            ),
-        ))
+        let mut error = Error::tool_failed(cmd, program, output.status, &output.stderr);
+        error.details_mut().diagnostics = parsed;
+        Err(error)
     }
 }
 
-fn spawn(cmd: &mut Command, program: &str) -> Result<(Child, JoinHandle<()>), Error> {
-    println!("running: {:?}", cmd);
+/// Turns the errors of the files which failed to compile out of `total` into
+/// one listing them all, unless there is only one.
+fn compile_failures(mut errors: Vec<Error>, total: usize) -> Result<(), Error> {
+    if errors.len() <= 1 {
+        return errors.pop().map_or(Ok(()), Err);
+    }
 
-    // Capture the standard error coming from these programs, and write it out
-    // with cargo:warning= prefixes. Note that this is a bit wonky to avoid
-    // requiring the output to be UTF-8, we instead just ship bytes from one
-    // location to another.
-    match cmd.stderr(Stdio::piped()).spawn() {
-        Ok(mut child) => {
-            let stderr = BufReader::new(child.stderr.take().unwrap());
-            let print = thread::spawn(move || {
-                for line in stderr.split(b'\n').filter_map(|l| l.ok()) {
-                    print!("cargo:warning=");
-                    std::io::stdout().write_all(&line).unwrap();
-                    println!("");
-                }
-            });
-            Ok((child, print))
+    let mut message = format!("{} of {} files failed to compile:", errors.len(), total);
+    for error in errors.iter() {
+        let file = match error.source_file() {
+            Some(file) => file.display().to_string(),
+            None => "<unknown>".to_string(),
+        };
+        message.push_str(&format!("\n  {}:", file));
+        let mut reported = false;
+        for diagnostic in error.diagnostics() {
+            if diagnostic.severity == Severity::Error {
+                message.push_str(&format!("\n    {}", diagnostic));
+                reported = true;
+            }
+        }
+        if !reported {
+            message.push_str(&format!("\n    {}", error.message));
+        }
+    }
+
+    let mut error = Error::new(ErrorKind::ToolExecError, &message);
+    error.details_mut().errors = errors;
+    Err(error)
+}
+
+fn run_output(
+    cmd: &mut Invocation,
+    program: &str,
+    log: &Logger,
+    runner: &Runner,
+) -> Result<Vec<u8>, Error> {
+    cmd.capture_stdout = true;
+    let output = execute(cmd, program, log, runner, true)?;
+
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(Error::tool_failed(
+            cmd,
+            program,
+            output.status,
+            &output.stderr,
+        ))
+    }
+}
+
+/// Runs `cmd` with `runner`, capturing its standard error, which is also
+/// written out with cargo:warning= prefixes as it comes if `stream` is set.
+fn execute(
+    cmd: &mut Invocation,
+    program: &str,
+    log: &Logger,
+    runner: &Runner,
+    stream: bool,
+) -> Result<Output, Error> {
+    log.log(LogLevel::Info, &format!("running: {:?}", cmd));
+    let result = if stream {
+        runner.run_streaming(cmd, &mut |line| {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            let _ = stdout.write_all(b"cargo:warning=");
+            let _ = stdout.write_all(line);
+            let _ = stdout.write_all(b"\n");
+        })
+    } else {
+        runner.run(cmd)
+    };
+    match result {
+        Ok(output) => {
+            log.log(LogLevel::Debug, &output.status.to_string());
+            Ok(output)
         }
         Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
             let extra = if cfg!(windows) {
@@ -2398,7 +4728,64 @@ fn spawn(cmd: &mut Command, program: &str) -> Result<(Child, JoinHandle<()>), Er
         Err(_) => Err(Error::new(
             ErrorKind::ToolExecError,
             &format!("Command {:?} with args {:?} failed to start.", cmd, program),
-        )),
+        )
+        .with_command(cmd)),
+    }
+}
+
+/// Writes out the diagnostics in the standard error of a tool as one
+/// cargo:warning= each, and returns them. Output which is not understood is
+/// written out line by line instead.
+///
+/// Note that this is a bit wonky to avoid requiring the output to be UTF-8,
+/// we instead just ship bytes from one location to another.
+fn print_stderr(stderr: &[u8], format: diagnostics::Format) -> Vec<Diagnostic> {
+    match diagnostics::parse(&String::from_utf8_lossy(stderr), format) {
+        Some(parsed) => {
+            print_diagnostics(&parsed);
+            parsed
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            if !stderr.is_empty() {
+                let lines = if stderr.ends_with(b"\n") {
+                    &stderr[..stderr.len() - 1]
+                } else {
+                    stderr
+                };
+                for line in lines.split(|&b| b == b'\n') {
+                    let _ = stdout.write_all(b"cargo:warning=");
+                    let _ = stdout.write_all(line);
+                    let _ = stdout.write_all(b"\n");
+                }
+            }
+            Vec::new()
+        }
+    }
+}
+
+/// Writes out the standard output of a tool which prints its diagnostics
+/// there, as one warning each like `print_stderr`, and returns them. Output
+/// which holds no diagnostic is passed through as it is.
+fn print_stdout(stdout: &[u8], format: diagnostics::Format) -> Vec<Diagnostic> {
+    match diagnostics::parse(&String::from_utf8_lossy(stdout), format) {
+        Some(parsed) => {
+            print_diagnostics(&parsed);
+            parsed
+        }
+        None => {
+            let _ = io::stdout().write_all(stdout);
+            Vec::new()
+        }
+    }
+}
+
+fn print_diagnostics(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics.iter() {
+        if diagnostic.severity != Severity::Note {
+            println!("cargo:warning={}", diagnostic);
+        }
     }
 }
 
@@ -2407,7 +4794,238 @@ fn fail(s: &str) -> ! {
     std::process::exit(1);
 }
 
-fn command_add_output_file(cmd: &mut Command, dst: &Path, msvc: bool, is_asm: bool, is_arm: bool) {
+/// Whether `target` is a wasm target compiled with a plain Clang, i.e. not
+/// through Emscripten.
+fn is_bare_wasm(target: &str) -> bool {
+    target == "wasm32-wasi" || target == "wasm32-unknown-wasi" || target == "wasm32-unknown-unknown"
+}
+
+/// Returns the Debian multiarch tuple of a Linux target, which names its
+/// library directories, e.g. `aarch64-linux-gnu` in `/usr/lib`.
+fn multiarch_tuple(target: &str) -> Option<String> {
+    let parts = target.split('-').collect::<Vec<_>>();
+    if parts.len() != 4 || parts[2] != "linux" {
+        return None;
+    }
+    let arch = match parts[0] {
+        "i586" | "i686" => "i386",
+        arch if arch.starts_with("arm") || arch.starts_with("thumb") => "arm",
+        arch => arch,
+    };
+    Some(format!("{}-linux-{}", arch, parts[3]))
+}
+
+/// Returns the target triple used by the NDK's Clang for an Android Rust
+/// target, without the API level suffix.
+fn android_clang_target(target: &str) -> String {
+    let mut parts = target.splitn(2, '-');
+    let arch = parts.next().unwrap_or("");
+    let rest = parts.next().unwrap_or("");
+    if arch.starts_with("arm") || arch.starts_with("thumb") {
+        format!("armv7a-{}", rest)
+    } else {
+        target.to_string()
+    }
+}
+
+/// Returns the `xcrun` name of the Apple SDK whose platform directory or SDK
+/// is named `name` in Xcode, e.g. `iphoneos` for `iPhoneOS`.
+fn apple_sdk_name(name: &str) -> Option<&'static str> {
+    match name {
+        "MacOSX" => Some("macosx"),
+        "iPhoneOS" => Some("iphoneos"),
+        "iPhoneSimulator" => Some("iphonesimulator"),
+        "AppleTVOS" => Some("appletvos"),
+        "AppleTVSimulator" => Some("appletvsimulator"),
+        "WatchOS" => Some("watchos"),
+        "WatchSimulator" => Some("watchsimulator"),
+        _ => None,
+    }
+}
+
+/// Copies the header `src` into the directory `include`, or its contents if it
+/// is a directory.
+fn install_header(src: &Path, include: &Path) -> io::Result<()> {
+    if src.is_dir() {
+        return copy_dir(src, include);
+    }
+    let name = src
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+    fs::create_dir_all(include)?;
+    fs::copy(src, include.join(name))?;
+    Ok(())
+}
+
+/// Copies the contents of the directory `src` into `dst`, recursively.
+fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    fs::File::create(path)?.write_all(contents)
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let dst = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dst)?;
+        } else {
+            fs::copy(entry.path(), dst)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path of the object for `file` mirroring its path under `dst`,
+/// unless that would not be under `dst`.
+fn mirrored_object_path(dst: &Path, file: &Path) -> Option<PathBuf> {
+    let relative = file.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+    if relative && file.file_name().is_some() {
+        Some(dst.join(file).with_extension("o"))
+    } else {
+        None
+    }
+}
+
+/// Returns a path for the object of `file` directly in `dst`, which is unique
+/// to the path of `file` as given and stable across builds.
+fn hashed_object_path(dst: &Path, file: &Path) -> Option<PathBuf> {
+    // 64-bit FNV-1a, as the hashers of the standard library may change.
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in file.to_string_lossy().bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    let name = Path::new(try_opt!(file.file_name())).with_extension("o");
+    Some(dst.join(format!("{:016x}-{}", hash, name.display())))
+}
+
+/// Moves the arguments of `cmd` to a response file next to `dst` if its command
+/// line is likely too long to be run, and passes `@<file>` instead.
+///
+/// The arguments are quoted following the rules of MSVC tools if
+/// `msvc_quoting` is set, and following the GNU ones otherwise.
+fn command_add_response_file(
+    cmd: &mut Invocation,
+    dst: &Path,
+    msvc_quoting: bool,
+) -> Result<(), Error> {
+    // Similar to https://github.com/rust-lang/rust/pull/47507
+    // and https://github.com/rust-lang/rust/pull/48548. Windows limits command
+    // lines to 32K, and batch scripts to 8K. On Unix a single argument must
+    // not exceed 128K, and all of them together with the environment are
+    // usually allowed a few megabytes.
+    let limit = if cfg!(windows) || msvc_quoting {
+        1024 * 6
+    } else {
+        1024 * 128
+    };
+    let estimated_command_line_len = cmd.args.iter().map(|a| a.len() + 3).sum::<usize>();
+    if estimated_command_line_len <= limit {
+        return Ok(());
+    }
+    let args = match cmd
+        .args
+        .iter()
+        .map(|a| a.to_str().map(|a| a.to_string()))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(args) => args,
+        // There is no portable way to write these, so try the long command
+        // line anyway.
+        None => return Ok(()),
+    };
+
+    let mut contents = Vec::new();
+    if msvc_quoting {
+        let mut s = String::from("\u{FEFF}"); // BOM
+        for arg in args {
+            msvc_quote(&arg, &mut s);
+            s.push('\n');
+        }
+        for code_unit in s.encode_utf16() {
+            contents.push(code_unit as u8);
+            contents.push((code_unit >> 8) as u8);
+        }
+    } else {
+        let mut s = String::new();
+        for arg in args {
+            gnu_quote(&arg, &mut s);
+            s.push('\n');
+        }
+        contents = s.into_bytes();
+    }
+
+    let mut rsp = OsString::from(dst);
+    rsp.push(".args");
+    write_file(Path::new(&rsp), &contents).map_err(|e| {
+        Error::new(
+            ErrorKind::IOError,
+            &format!(
+                "Failed to write response file {}: {}",
+                Path::new(&rsp).display(),
+                e
+            ),
+        )
+    })?;
+
+    let mut rsp_arg = OsString::from("@");
+    rsp_arg.push(rsp);
+    let args = mem::replace(&mut cmd.args, vec![rsp_arg]);
+    cmd.response_file_args = Some(args);
+    Ok(())
+}
+
+/// Quotes `arg` for a response file of GCC, Clang or GNU `ar`, where
+/// backslashes escape the next character.
+fn gnu_quote(arg: &str, out: &mut String) {
+    if arg.is_empty() {
+        out.push_str("\"\"");
+    }
+    for c in arg.chars() {
+        if c == '\\' || c == '"' || c == '\'' || c.is_whitespace() {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+/// Quotes `arg` for a response file of an MSVC tool, following the rules of
+/// `CommandLineToArgvW`: backslashes are only special in front of a quote.
+fn msvc_quote(arg: &str, out: &mut String) {
+    out.push('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+        } else {
+            if c == '"' {
+                // Double the backslashes, and escape the quote.
+                for _ in 0..backslashes + 1 {
+                    out.push('\\');
+                }
+            }
+            backslashes = 0;
+        }
+        out.push(c);
+    }
+    // Double trailing backslashes, which precede the closing quote.
+    for _ in 0..backslashes {
+        out.push('\\');
+    }
+    out.push('"');
+}
+
+fn command_add_output_file(
+    cmd: &mut Invocation,
+    dst: &Path,
+    msvc: bool,
+    is_asm: bool,
+    is_arm: bool,
+) {
     if msvc && is_asm && is_arm {
         cmd.arg("-o").arg(&dst);
     } else if msvc && is_asm {
@@ -0,0 +1,363 @@
+//! A reader for the `.pc` files of pkg-config, so that the flags of system
+//! libraries can be found without the `pkg-config` binary.
+//!
+//! Only what is needed to compile against and link to a package is
+//! supported: variables (including `pcfiledir`), `Cflags`, `Libs`, and the
+//! packages listed in `Requires` and `Requires.private`, which are looked up
+//! recursively. Version constraints are not checked.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+/// The flags needed to use a package and the packages it requires.
+#[derive(Debug, Default)]
+pub struct Package {
+    pub include_dirs: Vec<PathBuf>,
+    pub defines: Vec<(String, Option<String>)>,
+    /// Compiler flags other than `-I` and `-D`.
+    pub cflags: Vec<String>,
+    pub link_paths: Vec<PathBuf>,
+    pub libs: Vec<String>,
+    pub frameworks: Vec<String>,
+}
+
+/// Finds the package `name` in the directories `search`, and collects its
+/// flags along with those of the packages it requires.
+///
+/// If `sysroot` is given, absolute include and library directories are taken
+/// to be relative to it, like with `PKG_CONFIG_SYSROOT_DIR`.
+pub fn probe(name: &str, search: &[PathBuf], sysroot: Option<&Path>) -> io::Result<Package> {
+    let mut package = Package::default();
+    add_package(name, search, sysroot, true, &mut package, &mut Vec::new())?;
+    Ok(package)
+}
+
+fn add_package(
+    name: &str,
+    search: &[PathBuf],
+    sysroot: Option<&Path>,
+    link: bool,
+    package: &mut Package,
+    stack: &mut Vec<String>,
+) -> io::Result<()> {
+    // Packages required several times are visited again, to move their
+    // libraries after those of every package requiring them, but cycles are
+    // broken.
+    if stack.iter().any(|s| s == name) {
+        return Ok(());
+    }
+
+    let file_name = format!("{}.pc", name);
+    let path = search
+        .iter()
+        .map(|dir| dir.join(&file_name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "package `{}` was not found in the pkg-config search path {:?}",
+                    name, search
+                ),
+            )
+        })?;
+    let fields = parse(&path)?;
+    let field = |key: &str| fields.get(key).map(|s| &s[..]).unwrap_or("");
+
+    let sysrooted = |dir: &str| match sysroot {
+        Some(sysroot) if dir.starts_with('/') => sysroot.join(&dir[1..]),
+        _ => PathBuf::from(dir),
+    };
+
+    // Like pkg-config, leave out the directories the toolchain searches
+    // anyway, as adding them could change the order of the search. pkg-config
+    // also accepts the `CFlags` spelling of the field.
+    let cflags = if fields.contains_key("Cflags") {
+        field("Cflags")
+    } else {
+        field("CFlags")
+    };
+    let mut cflags = split_args(cflags).into_iter();
+    while let Some(flag) = cflags.next() {
+        if flag.starts_with("-I") {
+            let dir = if flag == "-I" {
+                cflags.next().unwrap_or_default()
+            } else {
+                flag[2..].to_string()
+            };
+            if dir == "/usr/include" {
+                continue;
+            }
+            let dir = sysrooted(&dir);
+            if !package.include_dirs.contains(&dir) {
+                package.include_dirs.push(dir);
+            }
+        } else if flag.starts_with("-D") {
+            let define = &flag[2..];
+            let define = match define.find('=') {
+                Some(i) => (define[..i].to_string(), Some(define[i + 1..].to_string())),
+                None => (define.to_string(), None),
+            };
+            if !package.defines.contains(&define) {
+                package.defines.push(define);
+            }
+        } else if !package.cflags.contains(&flag) {
+            package.cflags.push(flag);
+        }
+    }
+
+    if link {
+        let mut libs = split_args(field("Libs")).into_iter();
+        while let Some(flag) = libs.next() {
+            if flag.starts_with("-L") {
+                let dir = if flag == "-L" {
+                    libs.next().unwrap_or_default()
+                } else {
+                    flag[2..].to_string()
+                };
+                if is_system_lib_dir(&dir) {
+                    continue;
+                }
+                let dir = sysrooted(&dir);
+                if !package.link_paths.contains(&dir) {
+                    package.link_paths.push(dir);
+                }
+            } else if flag.starts_with("-l") {
+                let lib = &flag[2..];
+                // Libraries must come after the ones depending on them.
+                package.libs.retain(|l| l != lib);
+                package.libs.push(lib.to_string());
+            } else if flag == "-framework" {
+                if let Some(framework) = libs.next() {
+                    if !package.frameworks.contains(&framework) {
+                        package.frameworks.push(framework);
+                    }
+                }
+            }
+        }
+    }
+
+    // The headers of private requirements may still be included by the
+    // package's, but their libraries are only needed for static linking.
+    stack.push(name.to_string());
+    for required in requires(field("Requires")) {
+        add_package(&required, search, sysroot, link, package, stack)?;
+    }
+    for required in requires(field("Requires.private")) {
+        add_package(&required, search, sysroot, false, package, stack)?;
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Whether `dir` is one of the default library directories of the linker,
+/// including the multiarch ones of Debian, e.g. `/usr/lib/x86_64-linux-gnu`.
+fn is_system_lib_dir(dir: &str) -> bool {
+    let dir = dir.trim_right_matches('/');
+    ["/lib", "/lib64", "/usr/lib", "/usr/lib64"]
+        .iter()
+        .any(|&system| {
+            if !dir.starts_with(system) {
+                return false;
+            }
+            let rest = &dir[system.len()..];
+            if rest.is_empty() {
+                return true;
+            }
+            if !rest.starts_with('/') {
+                return false;
+            }
+            let multiarch = &rest[1..];
+            multiarch.contains("-linux-") && !multiarch.contains('/')
+        })
+}
+
+/// Reads the `.pc` file at `path`, returning its fields with variables
+/// expanded.
+fn parse(path: &Path) -> io::Result<HashMap<String, String>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    let mut variables = HashMap::new();
+    if let Some(dir) = path.parent() {
+        variables.insert("pcfiledir".to_string(), dir.to_string_lossy().into_owned());
+    }
+    let mut fields = HashMap::new();
+
+    let mut lines = contents.lines();
+    while let Some(line) = lines.next() {
+        let mut line = line.to_string();
+        while line.ends_with('\\') {
+            line.pop();
+            match lines.next() {
+                Some(next) => line.push_str(next),
+                None => break,
+            }
+        }
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => &line[..],
+        };
+        let line = line.trim();
+
+        // Keywords are followed by `:`, and variable definitions by `=`.
+        let end = line
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(line.len());
+        let (key, rest) = line.split_at(end);
+        let rest = rest.trim_left();
+        if key.is_empty() {
+            continue;
+        }
+        if rest.starts_with('=') {
+            let value = expand(rest[1..].trim(), &variables, path)?;
+            variables.insert(key.to_string(), value);
+        } else if rest.starts_with(':') {
+            let value = expand(rest[1..].trim(), &variables, path)?;
+            fields.insert(key.to_string(), value);
+        }
+    }
+    Ok(fields)
+}
+
+/// Replaces the `${name}` references to variables in `value`.
+fn expand(value: &str, variables: &HashMap<String, String>, path: &Path) -> io::Result<String> {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("$$") {
+            out.push('$');
+            rest = &rest[2..];
+        } else if rest.starts_with("${") {
+            let end = rest.find('}').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unterminated variable reference in {}", path.display()),
+                )
+            })?;
+            let (name, after) = (&rest[2..end], &rest[end + 1..]);
+            let value = variables.get(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("undefined variable `{}` in {}", name, path.display()),
+                )
+            })?;
+            out.push_str(value);
+            rest = after;
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Returns the names of the packages in a `Requires` field, leaving out their
+/// version constraints.
+fn requires(field: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut words = field
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|w| !w.is_empty());
+    while let Some(word) = words.next() {
+        match word {
+            "=" | "!=" | "<" | "<=" | ">" | ">=" => {
+                words.next();
+            }
+            _ => names.push(word.to_string()),
+        }
+    }
+    names
+}
+
+/// Splits flags like a shell would, handling quotes and backslashes.
+fn split_args(s: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if !c.is_whitespace() {
+            in_arg = true;
+        }
+        match c {
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(mem::replace(&mut arg, String::new()));
+                    in_arg = false;
+                }
+            }
+            '\\' => {
+                arg.extend(chars.next());
+            }
+            '\'' | '"' => {
+                while let Some(next) = chars.next() {
+                    if next == c {
+                        break;
+                    } else if next == '\\' && c == '"' {
+                        arg.extend(chars.next());
+                    } else {
+                        arg.push(next);
+                    }
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_system_lib_dir, probe, Package};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn probe_pc(name: &str, contents: &str) -> Package {
+        let dir = env::temp_dir().join(format!("cc-pkg-config-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join(format!("{}.pc", name)))
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+        probe(name, &[dir], None).unwrap()
+    }
+
+    #[test]
+    fn separate_link_path() {
+        let package = probe_pc(
+            "separate-link-path",
+            "Name: a\nLibs: -L /opt/a/lib -L /usr/lib -la\n",
+        );
+        assert_eq!(package.link_paths, [PathBuf::from("/opt/a/lib")]);
+        assert_eq!(package.libs, ["a"]);
+    }
+
+    #[test]
+    fn cflags_spelling() {
+        let package = probe_pc("cflags-spelling", "Name: b\nCFlags: -I/opt/b/include -DB\n");
+        assert_eq!(package.include_dirs, [PathBuf::from("/opt/b/include")]);
+        assert_eq!(package.defines, [("B".to_string(), None)]);
+    }
+
+    #[test]
+    fn system_lib_dirs() {
+        assert!(is_system_lib_dir("/usr/lib"));
+        assert!(is_system_lib_dir("/usr/lib64/"));
+        assert!(is_system_lib_dir("/usr/lib/x86_64-linux-gnu"));
+        assert!(!is_system_lib_dir("/usr/libfoo-linux-gnu"));
+        assert!(!is_system_lib_dir("/usr/lib\u{e9}"));
+        assert!(!is_system_lib_dir("/usr/lib/x86_64-linux-gnu/foo"));
+        assert!(!is_system_lib_dir("/opt/foo/lib"));
+    }
+}
@@ -0,0 +1,73 @@
+//! The checks of the invocations of tools, shared with the tests of this
+//! crate, whose shim compiler records them in files.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// An invocation of a tool of a fake toolchain.
+#[derive(Clone, Debug)]
+pub struct Execution {
+    program: PathBuf,
+    args: Vec<OsString>,
+}
+
+impl Execution {
+    #[doc(hidden)]
+    pub fn new(program: PathBuf, args: Vec<OsString>) -> Execution {
+        Execution {
+            program: program,
+            args: args,
+        }
+    }
+
+    /// Returns the path of the tool.
+    pub fn program(&self) -> &Path {
+        &self.program
+    }
+
+    /// Returns the arguments the tool was given.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// Whether `p` is one of the arguments.
+    pub fn has(&self, p: &OsStr) -> bool {
+        self.args.iter().any(|arg| arg == p)
+    }
+
+    /// Panics unless `p` is one of the arguments.
+    pub fn must_have<P: AsRef<OsStr>>(&self, p: P) -> &Execution {
+        if !self.has(p.as_ref()) {
+            panic!("didn't find {:?} in {:?}", p.as_ref(), self.args);
+        }
+        self
+    }
+
+    /// Panics if `p` is one of the arguments.
+    pub fn must_not_have<P: AsRef<OsStr>>(&self, p: P) -> &Execution {
+        if self.has(p.as_ref()) {
+            panic!("found {:?} in {:?}", p.as_ref(), self.args);
+        }
+        self
+    }
+
+    /// Panics unless the last `before` argument comes before the last `after`
+    /// one.
+    pub fn must_have_in_order<P, Q>(&self, before: P, after: Q) -> &Execution
+    where
+        P: AsRef<OsStr>,
+        Q: AsRef<OsStr>,
+    {
+        let (before, after) = (before.as_ref(), after.as_ref());
+        let before_position = self.args.iter().rposition(|x| x == before);
+        let after_position = self.args.iter().rposition(|x| x == after);
+        match (before_position, after_position) {
+            (Some(b), Some(a)) if b < a => {}
+            (b, a) => panic!(
+                "{:?} (last position: {:?}) did not appear before {:?} (last position: {:?})",
+                before, b, after, a
+            ),
+        };
+        self
+    }
+}
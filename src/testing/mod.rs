@@ -0,0 +1,306 @@
+//! Fake toolchains to test build scripts with, without a C compiler.
+//!
+//! A `Test` creates a temporary directory to use as the output directory of a
+//! `Build`, whose compiler and archiver are not run but recorded in process.
+//! Their outputs are created empty, so that the build succeeds, and the
+//! recorded invocations can then be checked:
+//!
+//! ```
+//! use cc::testing::Test;
+//!
+//! let test = Test::gnu();
+//! test.build()
+//!     .define("FOO", "1")
+//!     .file("foo.c")
+//!     .compile("foo");
+//!
+//! test.cmd(0).must_have("-DFOO=1").must_have("foo.c");
+//! test.cmd(1).must_have(test.dir().join("libfoo.a"));
+//! ```
+//!
+//! The checks of the flags supported by the compiler are not recorded, and
+//! only succeed for flags which do not change its output. Command lines long
+//! enough to be passed in a response file are recorded with the arguments
+//! read from the file in place of `@file`.
+//!
+//! This module is only available with the `testing` feature.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::{Arc, Mutex};
+
+use {Build, Invocation, Runner};
+
+mod execution;
+
+pub use self::execution::Execution;
+
+/// A fake GNU or MSVC toolchain, and the temporary directory it builds into.
+///
+/// The directory is removed when the `Test` is dropped.
+#[derive(Debug)]
+pub struct Test {
+    dir: PathBuf,
+    msvc: bool,
+    invocations: Arc<Mutex<Vec<Execution>>>,
+}
+
+impl Test {
+    /// Creates a toolchain with `cc` and `ar`, building for
+    /// `x86_64-unknown-linux-gnu`.
+    pub fn gnu() -> Test {
+        Test::new(false)
+    }
+
+    /// Creates a toolchain with `cl` and `lib.exe`, building for
+    /// `x86_64-pc-windows-msvc`.
+    pub fn msvc() -> Test {
+        Test::new(true)
+    }
+
+    fn new(msvc: bool) -> Test {
+        static COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+        // The directories of other processes running tests are skipped.
+        let mut dir;
+        loop {
+            let n = COUNT.fetch_add(1, Ordering::SeqCst);
+            dir = env::temp_dir().join(format!("cc-test-{}", n));
+            match fs::create_dir(&dir) {
+                Ok(()) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => panic!("failed to create {}: {}", dir.display(), e),
+            }
+        }
+        Test {
+            dir: dir,
+            msvc: msvc,
+            invocations: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns the temporary directory, which is the output directory of the
+    /// builds.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns a build using the fake toolchain.
+    pub fn build(&self) -> Build {
+        let mut cfg = Build::new();
+        let (target, compiler, archiver) = if self.msvc {
+            ("x86_64-pc-windows-msvc", "cl", "lib.exe")
+        } else {
+            ("x86_64-unknown-linux-gnu", "cc", "ar")
+        };
+        cfg.target(target)
+            .host(target)
+            .opt_level(2)
+            .debug(false)
+            .out_dir(&self.dir)
+            .compiler(self.dir.join(compiler))
+            .archiver(self.dir.join(archiver))
+            .runner(FakeRunner {
+                invocations: self.invocations.clone(),
+            });
+        cfg
+    }
+
+    /// Returns the invocations of the tools so far, in order.
+    pub fn invocations(&self) -> Vec<Execution> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    /// Returns the `i`th invocation of a tool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there were not that many invocations.
+    pub fn cmd(&self, i: usize) -> Execution {
+        let invocations = self.invocations.lock().unwrap();
+        match invocations.get(i) {
+            Some(execution) => execution.clone(),
+            None => panic!(
+                "only {} tool invocations were recorded, not {}",
+                invocations.len(),
+                i + 1
+            ),
+        }
+    }
+}
+
+impl Drop for Test {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Records the commands, and creates their outputs instead of running them.
+struct FakeRunner {
+    invocations: Arc<Mutex<Vec<Execution>>>,
+}
+
+impl Runner for FakeRunner {
+    fn run(&self, cmd: &mut Invocation) -> io::Result<Output> {
+        let program = PathBuf::from(cmd.get_program());
+        let mut args = Vec::new();
+        for arg in cmd.get_args() {
+            match arg.to_str() {
+                Some(a) if a.starts_with('@') => {
+                    args.extend(read_response_file(Path::new(&a[1..]))?)
+                }
+                _ => args.push(arg.to_owned()),
+            }
+        }
+        let output = Output {
+            status: success(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        };
+
+        let checking_flag = args
+            .iter()
+            .any(|a| Path::new(a).file_stem() == Some(OsStr::new("flag_check")));
+        if checking_flag {
+            return Ok(output);
+        }
+
+        let is_ar = program.to_string_lossy().ends_with("ar");
+        let mut outputs = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let arg = arg.to_string_lossy();
+            if arg == "-o" {
+                outputs.extend(args.get(i + 1).map(PathBuf::from));
+            } else if arg.starts_with("/Fo") {
+                outputs.push(PathBuf::from(&arg[3..]));
+            } else if arg.starts_with("/OUT:") {
+                outputs.push(PathBuf::from(&arg[5..]));
+            }
+        }
+        if is_ar {
+            outputs.extend(args.get(1).map(PathBuf::from));
+        }
+        for path in outputs {
+            File::create(path)?;
+        }
+
+        self.invocations
+            .lock()
+            .unwrap()
+            .push(Execution::new(program, args));
+        Ok(output)
+    }
+}
+
+/// Reads the arguments of a response file, in UTF-16 with MSVC quoting if it
+/// starts with a byte order mark, and in UTF-8 with GNU quoting otherwise.
+fn read_response_file(path: &Path) -> io::Result<Vec<OsString>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.starts_with(&[0xff, 0xfe]) {
+        let units = bytes[2..]
+            .chunks(2)
+            .map(|c| u16::from(c[0]) | u16::from(*c.get(1).unwrap_or(&0)) << 8)
+            .collect::<Vec<_>>();
+        Ok(msvc_unquote(&String::from_utf16_lossy(&units)))
+    } else {
+        Ok(gnu_unquote(&String::from_utf8_lossy(&bytes)))
+    }
+}
+
+/// Splits a GNU response file, where backslashes escape the next character.
+fn gnu_unquote(s: &str) -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                arg.extend(chars.next());
+                in_arg = true;
+            }
+            '"' | '\'' if quote == Some(c) => quote = None,
+            '"' | '\'' if quote.is_none() => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            c if c.is_whitespace() && quote.is_none() => {
+                if in_arg {
+                    args.push(mem::replace(&mut arg, String::new()).into());
+                    in_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg.into());
+    }
+    args
+}
+
+/// Splits an MSVC response file, following the rules of
+/// `CommandLineToArgvW`: backslashes are only special in front of a quote.
+fn msvc_unquote(s: &str) -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quoted = false;
+    let mut backslashes = 0;
+    for c in s.chars() {
+        if c == '\\' {
+            backslashes += 1;
+            in_arg = true;
+            continue;
+        }
+        if c == '"' {
+            arg.extend((0..backslashes / 2).map(|_| '\\'));
+            if backslashes % 2 == 0 {
+                quoted = !quoted;
+            } else {
+                arg.push('"');
+            }
+            backslashes = 0;
+            in_arg = true;
+            continue;
+        }
+        arg.extend((0..backslashes).map(|_| '\\'));
+        backslashes = 0;
+        if c.is_whitespace() && !quoted {
+            if in_arg {
+                args.push(mem::replace(&mut arg, String::new()).into());
+                in_arg = false;
+            }
+        } else {
+            arg.push(c);
+            in_arg = true;
+        }
+    }
+    arg.extend((0..backslashes).map(|_| '\\'));
+    if in_arg {
+        args.push(arg.into());
+    }
+    args
+}
+
+#[cfg(unix)]
+fn success() -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw(0)
+}
+
+#[cfg(windows)]
+fn success() -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(0)
+}
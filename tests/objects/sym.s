@@ -0,0 +1,22 @@
+# The source of the objects in this directory, which test the symbol table
+# readers of the built-in archiver (src/archive.rs). They were assembled with
+#
+#     llvm-mc -filetype=obj -triple x86_64-unknown-linux-gnu sym.s -o elf64.o
+#     llvm-mc -filetype=obj -triple i686-unknown-linux-gnu sym.s -o elf32.o
+#     llvm-mc -filetype=obj -triple x86_64-apple-macos10.12 sym.s -o macho.o
+#     llvm-mc -filetype=obj -triple x86_64-pc-windows-msvc sym.s -o coff.obj
+#     objcopy -I pe-x86-64 -O pe-bigobj-x86-64 coff.obj bigobj.obj
+#
+# and all define `foo`, `a_long_symbol_name` and the common `cvar`.
+
+	.text
+	.globl	foo
+foo:
+	call	ext
+	ret
+	.globl	a_long_symbol_name
+a_long_symbol_name:
+	ret
+bar:
+	ret
+	.comm	cvar,4
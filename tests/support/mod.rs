@@ -1,7 +1,6 @@
 #![allow(dead_code)]
 
 use std::env;
-use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::io::prelude::*;
 use std::path::PathBuf;
@@ -9,16 +8,17 @@ use std::path::PathBuf;
 use cc;
 use tempdir::TempDir;
 
+#[path = "../../src/testing/execution.rs"]
+mod execution;
+
+pub use self::execution::Execution;
+
 pub struct Test {
     pub td: TempDir,
     pub gcc: PathBuf,
     pub msvc: bool,
 }
 
-pub struct Execution {
-    args: Vec<String>,
-}
-
 impl Test {
     pub fn new() -> Test {
         let mut gcc = PathBuf::from(env::current_exe().unwrap());
@@ -85,49 +85,8 @@ impl Test {
             .unwrap()
             .read_to_string(&mut s)
             .unwrap();
-        Execution {
-            args: s.lines().map(|s| s.to_string()).collect(),
-        }
-    }
-}
-
-impl Execution {
-    pub fn must_have<P: AsRef<OsStr>>(&self, p: P) -> &Execution {
-        if !self.has(p.as_ref()) {
-            panic!("didn't find {:?} in {:?}", p.as_ref(), self.args);
-        } else {
-            self
-        }
-    }
-
-    pub fn must_not_have<P: AsRef<OsStr>>(&self, p: P) -> &Execution {
-        if self.has(p.as_ref()) {
-            panic!("found {:?}", p.as_ref());
-        } else {
-            self
-        }
-    }
-
-    pub fn has(&self, p: &OsStr) -> bool {
-        self.args.iter().any(|arg| OsStr::new(arg) == p)
-    }
-
-    pub fn must_have_in_order(&self, before: &str, after: &str) -> &Execution {
-        let before_position = self
-            .args
-            .iter()
-            .rposition(|x| OsStr::new(x) == OsStr::new(before));
-        let after_position = self
-            .args
-            .iter()
-            .rposition(|x| OsStr::new(x) == OsStr::new(after));
-        match (before_position, after_position) {
-            (Some(b), Some(a)) if b < a => {}
-            (b, a) => panic!(
-                "{:?} (last position: {:?}) did not appear before {:?} (last position: {:?})",
-                before, b, after, a
-            ),
-        };
-        self
+        let mut lines = s.lines();
+        let program = PathBuf::from(lines.next().unwrap());
+        Execution::new(program, lines.map(|s| s.into()).collect())
     }
 }
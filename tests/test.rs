@@ -113,22 +113,25 @@ fn gnu_warnings_overridable() {
 
 #[test]
 fn gnu_no_warnings_if_cflags() {
-    env::set_var("CFLAGS", "-Wflag-does-not-exist");
     let test = Test::gnu();
-    test.gcc().file("foo.c").compile("foo");
+    test.gcc()
+        .__set_env("CFLAGS", "-Wflag-does-not-exist")
+        .file("foo.c")
+        .compile("foo");
 
     test.cmd(0).must_not_have("-Wall").must_not_have("-Wextra");
-    env::set_var("CFLAGS", "");
 }
 
 #[test]
 fn gnu_no_warnings_if_cxxflags() {
-    env::set_var("CXXFLAGS", "-Wflag-does-not-exist");
     let test = Test::gnu();
-    test.gcc().file("foo.c").compile("foo");
+    test.gcc()
+        .cpp(true)
+        .__set_env("CXXFLAGS", "-Wflag-does-not-exist")
+        .file("foo.cpp")
+        .compile("foo");
 
     test.cmd(0).must_not_have("-Wall").must_not_have("-Wextra");
-    env::set_var("CXXFLAGS", "");
 }
 
 #[test]
@@ -146,6 +149,127 @@ fn gnu_x86_64() {
     }
 }
 
+#[test]
+fn gnu_apple_darwin() {
+    for &(arch, version) in &[("x86_64", "10.9"), ("aarch64", "11.0")] {
+        let target = format!("{}-apple-darwin", arch);
+        let test = Test::gnu();
+        test.gcc()
+            .target(&target)
+            .host(&target)
+            .__set_env("MACOSX_DEPLOYMENT_TARGET", version)
+            .file("foo.c")
+            .compile("foo");
+
+        test.cmd(0)
+            .must_have(format!("-mmacosx-version-min={}", version))
+            .must_not_have("-arch");
+    }
+}
+
+#[test]
+fn gnu_apple_bitcode() {
+    let target = "x86_64-apple-darwin";
+    let test = Test::gnu();
+    test.gcc()
+        .target(target)
+        .host(target)
+        .file("foo.c")
+        .compile("foo");
+    test.cmd(0)
+        .must_not_have("-fembed-bitcode")
+        .must_not_have("-fembed-bitcode-marker");
+
+    let test = Test::gnu();
+    test.gcc()
+        .target(target)
+        .host(target)
+        .bitcode(cc::Bitcode::Marker)
+        .file("foo.c")
+        .compile("foo");
+    test.cmd(0).must_have("-fembed-bitcode-marker");
+}
+
+#[test]
+fn gnu_apple_sdkroot() {
+    let target = "aarch64-apple-ios";
+    let test = Test::gnu();
+    test.gcc()
+        .target(target)
+        .host("x86_64-unknown-linux-gnu")
+        .__set_env("SDKROOT_aarch64-apple-ios", "/path/to/iPhoneOS.sdk")
+        .file("foo.c")
+        .compile("foo");
+
+    test.cmd(0)
+        .must_have_in_order("-arch", "arm64")
+        .must_have_in_order("-isysroot", "/path/to/iPhoneOS.sdk");
+}
+
+#[test]
+fn gnu_apple_sdkroot_of_other_platform() {
+    use std::io;
+    use std::process::Output;
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl cc::Runner for Recorder {
+        fn run(&self, cmd: &mut cc::Invocation) -> io::Result<Output> {
+            if cmd.get_program() == "xcrun" {
+                let args = cmd
+                    .get_args()
+                    .iter()
+                    .map(|a| a.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>();
+                self.0.lock().unwrap().push(args.join(" "));
+                return Err(io::Error::new(io::ErrorKind::NotFound, "no xcrun"));
+            }
+            cc::ProcessRunner.run(cmd)
+        }
+    }
+
+    let test = Test::gnu();
+    let xcrun = Arc::new(Mutex::new(Vec::new()));
+    let sdkroot = "/Applications/Xcode.app/Contents/Developer/Platforms/\
+                   MacOSX.platform/Developer/SDKs/MacOSX.sdk";
+    for &sdkroot in &[sdkroot, "/usr"] {
+        let err = test
+            .gcc()
+            .runner(Recorder(xcrun.clone()))
+            .target("aarch64-apple-ios")
+            .host("x86_64-unknown-linux-gnu")
+            .__set_env("SDKROOT", sdkroot)
+            .file("foo.c")
+            .try_compile("foo")
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("Unable to find the iphoneos SDK"));
+    }
+    assert_eq!(
+        *xcrun.lock().unwrap(),
+        [
+            "--show-sdk-path --sdk iphoneos",
+            "--show-sdk-path --sdk iphoneos"
+        ]
+    );
+}
+
+#[test]
+fn gnu_apple_sdk_not_found() {
+    if cfg!(target_os = "macos") {
+        return;
+    }
+    let test = Test::gnu();
+    let err = test
+        .gcc()
+        .target("aarch64-apple-tvos")
+        .host("x86_64-unknown-linux-gnu")
+        .file("foo.c")
+        .try_compile("foo")
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("SDKROOT"));
+}
+
 #[test]
 fn gnu_x86_64_no_pic() {
     for vendor in &["unknown-linux-gnu", "apple-darwin"] {
@@ -207,6 +331,791 @@ fn gnu_x86_64_no_plt() {
     test.cmd(0).must_have("-fno-plt");
 }
 
+#[test]
+fn gnu_riscv() {
+    for &(target, gcc, march, mabi) in &[
+        (
+            "riscv32imac-unknown-none-elf",
+            "riscv32-unknown-elf-gcc",
+            "-march=rv32imac",
+            "-mabi=ilp32",
+        ),
+        (
+            "riscv64gc-unknown-linux-gnu",
+            "riscv64-linux-gnu-gcc",
+            "-march=rv64gc",
+            "-mabi=lp64d",
+        ),
+    ] {
+        let test = Test::gnu();
+        test.shim(gcc);
+        test.gcc()
+            .target(target)
+            .host("x86_64-unknown-linux-gnu")
+            .file("foo.c")
+            .compile("foo");
+
+        test.cmd(0).must_have(march).must_have(mabi);
+    }
+}
+
+/// Writes a GNU archive with two members named `util.o`.
+fn vendor_archive(path: &std::path::Path) {
+    let mut archive = b"!<arch>\n".to_vec();
+    for data in &["one\n", "two\n"] {
+        let header = format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            "util.o/",
+            0,
+            0,
+            0,
+            644,
+            data.len()
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(data.as_bytes());
+    }
+    std::fs::write(path, archive).unwrap();
+}
+
+#[test]
+fn gnu_merge_archive() {
+    let test = Test::gnu();
+    vendor_archive(&test.td.path().join("libvendor.a"));
+    test.gcc()
+        .file("foo.c")
+        .archive(test.td.path().join("libvendor.a"))
+        .compile("foo");
+
+    let members = test.td.path().join("libfoo.members");
+    test.cmd(1)
+        .must_have(test.td.path().join("foo.o"))
+        .must_have(members.join("util.o"))
+        .must_have(members.join("1-util.o"));
+    assert_eq!(std::fs::read(members.join("util.o")).unwrap(), b"one\n");
+    assert_eq!(std::fs::read(members.join("1-util.o")).unwrap(), b"two\n");
+}
+
+#[test]
+fn gnu_merge_archive_builtin() {
+    let test = Test::gnu();
+    vendor_archive(&test.td.path().join("libvendor.a"));
+    std::fs::write(test.td.path().join("foo.o"), b"foo").unwrap();
+    test.gcc()
+        .builtin_archiver(true)
+        .file("foo.c")
+        .archive(test.td.path().join("libvendor.a"))
+        .compile("foo");
+
+    let archive = std::fs::read(test.td.path().join("libfoo.a")).unwrap();
+    let one = b"util.o/         0           0     0     644     4         `\none\n";
+    let two = b"1-util.o/       0           0     0     644     4         `\ntwo\n";
+    assert!(archive.windows(one.len()).any(|w| w == &one[..]));
+    assert!(archive.ends_with(two));
+    assert!(!test.td.path().join("libfoo.members").exists());
+}
+
+#[test]
+fn msvc_merge_archive() {
+    let test = Test::msvc();
+    test.gcc()
+        .file("foo.c")
+        .archive("vendor.lib")
+        .compile("foo");
+
+    test.cmd(1).must_have("vendor.lib");
+}
+
+#[test]
+fn gnu_archiver_from_cross_prefix() {
+    let test = Test::gnu();
+    test.shim("aarch64-linux-gnu-gcc")
+        .shim("aarch64-linux-gnu-ar");
+    let archiver = test
+        .gcc()
+        .target("aarch64-unknown-linux-gnu")
+        .host("x86_64-unknown-linux-gnu")
+        .get_archiver();
+
+    assert_eq!(
+        archiver.path(),
+        test.td
+            .path()
+            .join(format!("aarch64-linux-gnu-ar{}", env::consts::EXE_SUFFIX))
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn gnu_archiver_from_llvm_installation() {
+    let test = Test::gnu();
+    std::fs::create_dir_all(test.td.path().join("llvm/bin")).unwrap();
+    test.shim("llvm/bin/clang").shim("llvm-ar");
+    let clang = test.td.path().join("clang");
+    std::os::unix::fs::symlink(test.td.path().join("llvm/bin/clang"), &clang).unwrap();
+
+    // The `llvm-ar` next to the link belongs to another installation.
+    let archiver = test.gcc().compiler(&clang).get_archiver();
+    assert_eq!(archiver.path(), std::path::Path::new("ar"));
+
+    test.shim("llvm/bin/llvm-ar");
+    let archiver = test.gcc().compiler(&clang).get_archiver();
+    assert_eq!(archiver.path(), test.td.path().join("llvm/bin/llvm-ar"));
+}
+
+#[test]
+fn gnu_archiver_from_env() {
+    let test = Test::gnu();
+    test.shim("arm-linux-gnueabihf-gcc")
+        .shim("arm-linux-gnueabihf-ar");
+    let archiver = test
+        .gcc()
+        .target("armv7-unknown-linux-gnueabihf")
+        .host("x86_64-unknown-linux-gnu")
+        .__set_env("AR_armv7-unknown-linux-gnueabihf", "my-ar")
+        .get_archiver();
+
+    assert_eq!(archiver.path(), std::path::Path::new("my-ar"));
+}
+
+#[test]
+fn gnu_builtin_archiver() {
+    let test = Test::gnu();
+    std::fs::write(test.td.path().join("foo.o"), b"not really an object").unwrap();
+    test.gcc()
+        .builtin_archiver(true)
+        .file("foo.c")
+        .compile("foo");
+
+    // Only the compiler ran, not `ar`.
+    test.cmd(0).must_have("foo.c");
+    assert!(!test.td.path().join("out1").exists());
+
+    let archive = std::fs::read(test.td.path().join("libfoo.a")).unwrap();
+    assert!(archive.starts_with(b"!<arch>\n/               0           0     0     0"));
+    let member =
+        b"foo.o/          0           0     0     644     20        `\nnot really an object";
+    assert!(archive.ends_with(member));
+
+    test.gcc()
+        .builtin_archiver(true)
+        .file("foo.c")
+        .compile("foo");
+    let again = std::fs::read(test.td.path().join("libfoo.a")).unwrap();
+    assert_eq!(archive, again);
+}
+
+#[test]
+fn gnu_reproducible() {
+    let test = Test::gnu();
+    test.gcc()
+        .reproducible(true)
+        .remap_path_prefix("/tmp/out", "/out")
+        .__set_env(
+            "CARGO_ENCODED_RUSTFLAGS",
+            "-Copt-level=3\u{1f}--remap-path-prefix\u{1f}/home/me/src=/src",
+        )
+        .file("foo.c")
+        .compile("foo");
+
+    // The first command checks that `-ffile-prefix-map` is supported.
+    test.cmd(0).must_have("-ffile-prefix-map=a=b");
+    test.cmd(1)
+        .must_have("-ffile-prefix-map=/home/me/src=/src")
+        .must_have("-ffile-prefix-map=/tmp/out=/out")
+        .must_have_in_order("-ffile-prefix-map=/tmp/out=/out", "foo.c")
+        .must_not_have("-fdebug-prefix-map=/tmp/out=/out");
+    test.cmd(2).must_have("crsD");
+}
+
+#[test]
+fn gnu_reproducible_checks_prefix_map_once() {
+    use std::io;
+    use std::process::Output;
+    use std::sync::{Arc, Mutex};
+
+    // Fails the checks of flags, counting them.
+    struct FailChecks(Arc<Mutex<usize>>);
+
+    impl cc::Runner for FailChecks {
+        fn run(&self, cmd: &mut cc::Invocation) -> io::Result<Output> {
+            if cmd
+                .get_args()
+                .iter()
+                .any(|a| a.to_string_lossy().contains("flag_check"))
+            {
+                *self.0.lock().unwrap() += 1;
+                return Err(io::Error::new(io::ErrorKind::NotFound, "no checks"));
+            }
+            cc::ProcessRunner.run(cmd)
+        }
+    }
+
+    let test = Test::gnu();
+    let checks = Arc::new(Mutex::new(0));
+    test.gcc()
+        .runner(FailChecks(checks.clone()))
+        .flag("-fdiagnostics-format=text")
+        .reproducible(true)
+        .remap_path_prefix("/tmp/out", "/out")
+        .file("foo.c")
+        .file("bar.c")
+        .file("baz.c")
+        .compile("foo");
+
+    assert_eq!(*checks.lock().unwrap(), 1);
+    for i in 0..3 {
+        test.cmd(i).must_have("-fdebug-prefix-map=/tmp/out=/out");
+    }
+}
+
+#[test]
+fn gnu_object_names_are_unique() {
+    let test = Test::gnu();
+    test.gcc()
+        .file("../a/util.c")
+        .file("../b/util.c")
+        .file("foo.c")
+        .file("foo.cpp")
+        .file("bar.c")
+        .compile("foo");
+
+    test.cmd(5)
+        .must_have(test.td.path().join("bar.o"))
+        .must_not_have(test.td.path().join("util.o"))
+        .must_not_have(test.td.path().join("foo.o"));
+    let ar = std::fs::read_to_string(test.td.path().join("out5")).unwrap();
+    let mut objects = ar.lines().filter(|l| l.ends_with(".o")).collect::<Vec<_>>();
+    objects.sort();
+    objects.dedup();
+    assert_eq!(objects.len(), 5);
+    for obj in objects {
+        assert_eq!(std::path::Path::new(obj).parent(), Some(test.td.path()));
+    }
+}
+
+#[test]
+fn gnu_object_names_do_not_depend_on_package_location() {
+    let names = (0..2)
+        .map(|_| {
+            let test = Test::gnu();
+            test.gcc()
+                .__set_env("CARGO_MANIFEST_DIR", test.td.path())
+                .file(test.td.path().join("src/foo.c"))
+                .compile("foo");
+            let ar = std::fs::read_to_string(test.td.path().join("out1")).unwrap();
+            let obj = ar.lines().find(|l| l.ends_with(".o")).unwrap().to_string();
+            std::path::Path::new(&obj)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(names[0], names[1]);
+}
+
+#[test]
+fn gnu_response_file() {
+    let test = Test::gnu();
+    let mut build = test.gcc();
+    for i in 0..3000 {
+        build.include(format!(
+            "a/rather/long/path/to/some/include/directory/{}",
+            i
+        ));
+    }
+    build
+        .include("dir with \"spaces\"")
+        .file("foo.c")
+        .compile("foo");
+
+    let rsp = test.td.path().join("foo.o.args");
+    test.cmd(0)
+        .must_have(format!("@{}", rsp.display()))
+        .must_not_have("foo.c");
+    let args = std::fs::read_to_string(&rsp).unwrap();
+    assert!(args.contains("-I\na/rather/long/path/to/some/include/directory/2999\n"));
+    assert!(args.contains("-I\ndir\\ with\\ \\\"spaces\\\"\n"));
+    assert!(args.ends_with("-c\nfoo.c\n"));
+}
+
+#[test]
+fn gnu_include_from_dep() {
+    let test = Test::gnu();
+    let include = env::join_paths(["/dep/a/include", "/dep/b/include"]).unwrap();
+    test.gcc()
+        .__set_env("DEP_TEST_INCLUDE_INCLUDE", include)
+        .__set_env("DEP_TEST_INCLUDE_ROOT", "/dep/ignored")
+        .__set_env("DEP_TEST_ROOT_ROOT", "/dep/root")
+        .include_from_dep("test-include")
+        .include_from_dep("test_root")
+        .include_from_dep("test-missing")
+        .file("foo.c")
+        .compile("foo");
+
+    test.cmd(0)
+        .must_have("/dep/a/include")
+        .must_have_in_order("/dep/a/include", "/dep/b/include")
+        .must_have(std::path::Path::new("/dep/root").join("include"))
+        .must_not_have("/dep/ignored/include");
+}
+
+#[test]
+fn gnu_export_header() {
+    let test = Test::gnu();
+    let headers = test.td.path().join("headers");
+    std::fs::create_dir_all(headers.join("sub")).unwrap();
+    std::fs::write(headers.join("foo.h"), "").unwrap();
+    std::fs::write(headers.join("sub/bar.h"), "").unwrap();
+    std::fs::write(test.td.path().join("single.h"), "").unwrap();
+    test.gcc()
+        .export_header(&headers)
+        .export_header(test.td.path().join("single.h"))
+        .file("foo.c")
+        .compile("foo");
+
+    let include = test.td.path().join("include");
+    assert!(include.join("foo.h").is_file());
+    assert!(include.join("sub/bar.h").is_file());
+    assert!(include.join("single.h").is_file());
+}
+
+#[test]
+fn gnu_import_pkg_config() {
+    let test = Test::gnu();
+    let pc = test.td.path().join("pkgconfig");
+    std::fs::create_dir_all(&pc).unwrap();
+    std::fs::write(
+        pc.join("foo.pc"),
+        "# A comment\n\
+         prefix=/opt/foo\n\
+         includedir=${prefix}/include\n\
+         \n\
+         Name: foo\n\
+         Version: 1.0\n\
+         Requires: bar >= 2.0, baz\n\
+         Cflags: -I${includedir}/foo -DFOO=1 -DBAR -pthread\n\
+         Libs: -L${prefix}/lib -lfoo\n",
+    )
+    .unwrap();
+    std::fs::write(
+        pc.join("bar.pc"),
+        "Name: bar\n\
+         Requires.private: baz\n\
+         Cflags: -I \"${pcfiledir}/bar dir\"\n",
+    )
+    .unwrap();
+    std::fs::write(pc.join("baz.pc"), "Name: baz\nCflags: -I/opt/baz\n").unwrap();
+
+    let mut build = test.gcc();
+    build
+        .__set_env("PKG_CONFIG_PATH_x86_64-unknown-linux-gnu", &pc)
+        .__set_env("PKG_CONFIG_LIBDIR_x86_64-unknown-linux-gnu", "");
+    build.try_import_pkg_config("foo").unwrap();
+    assert!(build.try_import_pkg_config("missing").is_err());
+    build.file("foo.c").compile("foo");
+
+    test.cmd(0)
+        .must_have("/opt/foo/include/foo")
+        .must_have(pc.join("bar dir"))
+        .must_have("/opt/baz")
+        .must_have("-DFOO=1")
+        .must_have("-DBAR")
+        .must_have("-pthread");
+}
+
+#[test]
+fn gnu_diagnostics() {
+    let test = Test::gnu();
+    let mut build = test.gcc();
+    build
+        .flag("-fdiagnostics-format=text")
+        .__set_env(
+            "GCCTEST_STDERR",
+            "In file included from foo.c:1:\n\
+             bar.h:3:5: warning: unused variable 'x' [-Wunused-variable]\n\
+             \x20   3 |     int x;\n\
+             \x20     |         ^\n\
+             foo.c:7:1: error: expected ';' before '}' token\n\
+             foo.c:7: note: in this function\n",
+        )
+        .file("foo.c")
+        .compile("foo");
+
+    test.cmd(0).must_not_have("-fdiagnostics-format=json");
+    let diagnostics = build.diagnostics();
+    assert_eq!(diagnostics.len(), 3);
+    assert_eq!(diagnostics[0].file(), Some(std::path::Path::new("bar.h")));
+    assert_eq!(diagnostics[0].line(), Some(3));
+    assert_eq!(diagnostics[0].column(), Some(5));
+    assert_eq!(diagnostics[0].severity(), cc::Severity::Warning);
+    assert_eq!(diagnostics[0].code(), Some("-Wunused-variable"));
+    assert_eq!(diagnostics[0].message(), "unused variable 'x'");
+    assert_eq!(diagnostics[1].severity(), cc::Severity::Error);
+    assert_eq!(diagnostics[1].code(), None);
+    assert_eq!(diagnostics[2].severity(), cc::Severity::Note);
+    assert_eq!(diagnostics[2].column(), None);
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "bar.h:3:5: warning: unused variable 'x' [-Wunused-variable]"
+    );
+}
+
+#[test]
+fn gnu_json_diagnostics() {
+    let test = Test::gnu();
+    let mut build = test.gcc();
+    build
+        .__set_env(
+            "GCCTEST_STDERR",
+            r#"[{"kind": "warning", "message": "unused \"x\"", "option": "-Wunused-variable", "locations": [{"caret": {"file": "foo.c", "line": 2, "column": 9}}], "children": [{"kind": "note", "message": "declared here", "locations": []}]}]"#,
+        )
+        .file("foo.c")
+        .compile("foo");
+
+    let diagnostics = build.diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].file(), Some(std::path::Path::new("foo.c")));
+    assert_eq!(diagnostics[0].line(), Some(2));
+    assert_eq!(diagnostics[0].column(), Some(9));
+    assert_eq!(diagnostics[0].code(), Some("-Wunused-variable"));
+    assert_eq!(diagnostics[0].message(), "unused \"x\"");
+    assert_eq!(diagnostics[1].severity(), cc::Severity::Note);
+    assert_eq!(diagnostics[1].file(), None);
+}
+
+#[test]
+fn gnu_diagnostics_per_compilation() {
+    let test = Test::gnu();
+    let mut build = test.gcc();
+    build
+        .flag("-fdiagnostics-format=text")
+        .__set_env("GCCTEST_STDERR", "foo.c:1:1: warning: one\n")
+        .file("foo.c")
+        .compile("foo");
+
+    let mut clone = build.clone();
+    clone
+        .__set_env("GCCTEST_STDERR", "foo.c:2:1: warning: two\n")
+        .compile("foo");
+    assert_eq!(clone.diagnostics().len(), 1);
+    assert_eq!(clone.diagnostics()[0].message(), "two");
+    assert_eq!(build.diagnostics().len(), 1);
+    assert_eq!(build.diagnostics()[0].message(), "one");
+
+    build.compile("foo");
+    assert_eq!(build.diagnostics().len(), 1);
+    assert_eq!(build.report().objects().len(), 1);
+}
+
+#[test]
+fn gnu_failure() {
+    let test = Test::gnu();
+    let err = test
+        .gcc()
+        .__set_env("GCCTEST_STDERR", "oops\n")
+        .__set_env("GCCTEST_EXIT_CODE", "3")
+        .file("foo.c")
+        .try_compile("foo")
+        .unwrap_err();
+
+    assert_eq!(err.source_file(), Some(std::path::Path::new("foo.c")));
+    assert_eq!(err.args()[0], "cc");
+    assert!(err.args().iter().any(|a| a == "foo.c"));
+    assert_eq!(err.status().and_then(|s| s.code()), Some(3));
+    assert_eq!(err.stderr(), Some("oops\n"));
+}
+
+#[test]
+fn gnu_keep_going() {
+    let test = Test::gnu();
+    let mut build = test.gcc();
+    build
+        .flag("-fdiagnostics-format=text")
+        .__set_env("GCCTEST_STDERR", "x.h:1:2: error: broken\n")
+        .__set_env("GCCTEST_EXIT_CODE", "1")
+        .file("foo.c")
+        .file("bar.c");
+    let err = build.try_compile("foo").unwrap_err();
+    assert!(err.errors().is_empty());
+    assert!(!test.td.path().join("out1").exists());
+
+    let err = build.keep_going(true).try_compile("foo").unwrap_err();
+    assert_eq!(err.errors().len(), 2);
+    assert_eq!(
+        err.errors()[1].source_file(),
+        Some(std::path::Path::new("bar.c"))
+    );
+    assert_eq!(err.errors()[1].diagnostics()[0].message(), "broken");
+    assert!(test.td.path().join("out2").exists());
+    assert!(!test.td.path().join("out3").exists());
+}
+
+#[test]
+fn gnu_failure_with_response_file() {
+    let test = Test::gnu();
+    let mut build = test.gcc();
+    for i in 0..3000 {
+        build.include(format!(
+            "a/rather/long/path/to/some/include/directory/{}",
+            i
+        ));
+    }
+    let err = build
+        .__set_env("GCCTEST_EXIT_CODE", "1")
+        .file("foo.c")
+        .try_compile("foo")
+        .unwrap_err();
+
+    test.cmd(0)
+        .must_have(format!("@{}", test.td.path().join("foo.o.args").display()));
+    assert_eq!(err.args()[0], "cc");
+    assert!(err.args().iter().any(|a| a == "foo.c"));
+    assert!(!err
+        .args()
+        .iter()
+        .any(|a| a.to_string_lossy().starts_with('@')));
+}
+
+#[test]
+fn gnu_plan() {
+    let test = Test::gnu();
+    let plan = test
+        .gcc()
+        .reproducible(true)
+        .remap_path_prefix("/tmp/out", "/out")
+        .flag_if_supported("-fsome-new-flag")
+        .file("src/foo.c")
+        .plan("foo");
+
+    assert!(!test.td.path().join("out0").exists());
+    assert!(!test.td.path().join("src").exists());
+    assert_eq!(plan.commands().len(), 2);
+    let compile = &plan.commands()[0];
+    assert_eq!(compile.program(), "cc");
+    assert!(compile.args().iter().any(|a| a == "src/foo.c"));
+    assert!(!compile.args().iter().any(|a| a == "-fsome-new-flag"));
+    assert!(!compile
+        .args()
+        .iter()
+        .any(|a| a == "-fdiagnostics-format=json"));
+    assert_eq!(
+        compile.conditional_args(),
+        ["-fsome-new-flag", "-fdiagnostics-format=json"]
+    );
+    assert!(compile
+        .args()
+        .iter()
+        .any(|a| a == "-fdebug-prefix-map=/tmp/out=/out"));
+    assert!(compile.env().iter().any(|(k, _)| k == "GCCTEST_OUT_DIR"));
+    let archive = &plan.commands()[1];
+    assert_eq!(archive.program(), "ar");
+    assert_eq!(archive.args()[0], "crsD");
+    assert!(plan
+        .metadata()
+        .iter()
+        .any(|m| m == "cargo:rustc-link-lib=static=foo"));
+}
+
+#[test]
+fn gnu_compile_intermediates() {
+    let test = Test::gnu();
+    let objects = test
+        .gcc()
+        .file("foo.c")
+        .file("src/bar.c")
+        .compile_intermediates();
+
+    assert_eq!(objects.len(), 2);
+    assert_eq!(objects[0].0, std::path::Path::new("foo.c"));
+    assert_eq!(objects[0].1, test.td.path().join("foo.o"));
+    assert_eq!(objects[1].0, std::path::Path::new("src/bar.c"));
+    assert_eq!(objects[1].1, test.td.path().join("src/bar.o"));
+    test.cmd(0).must_have("-c").must_have("foo.c");
+    test.cmd(1).must_have("-c").must_have("src/bar.c");
+    assert!(!test.td.path().join("out2").exists());
+}
+
+#[test]
+fn gnu_report() {
+    let test = Test::gnu();
+    let mut build = test.gcc();
+    build.report_json(true).file("foo.c").file("bar.c");
+    build.compile("foo");
+
+    let report = build.report();
+    assert_eq!(report.library(), "foo");
+    assert_eq!(report.objects().len(), 2);
+    assert_eq!(report.objects()[0].source(), std::path::Path::new("foo.c"));
+    assert_eq!(report.objects()[0].compiler(), std::path::Path::new("cc"));
+    assert_eq!(report.objects()[0].status().and_then(|s| s.code()), Some(0));
+    assert!(report.archive_time().is_some());
+
+    let json = std::fs::read_to_string(test.td.path().join("cc-report-foo.json")).unwrap();
+    assert_eq!(json, report.to_json());
+    assert!(json.contains("\"source\": \"bar.c\""));
+}
+
+#[test]
+fn gnu_log_sink() {
+    use std::sync::{Arc, Mutex};
+
+    let test = Test::gnu();
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let sink = messages.clone();
+    test.gcc()
+        .log_sink(move |level, message| sink.lock().unwrap().push((level, message.to_string())))
+        .file("foo.c")
+        .compile("foo");
+
+    let messages = messages.lock().unwrap();
+    assert!(messages
+        .iter()
+        .any(|(level, m)| *level == cc::LogLevel::Info && m.starts_with("running: ")));
+    assert!(messages
+        .iter()
+        .any(|(level, m)| *level == cc::LogLevel::Debug && m.starts_with("CFLAGS = ")));
+    assert!(messages.iter().all(|(_, m)| !m.starts_with("cargo:")));
+}
+
+#[test]
+fn gnu_log_sink_flag_check() {
+    use std::sync::{Arc, Mutex};
+
+    let test = Test::gnu();
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let sink = messages.clone();
+    test.gcc()
+        .cargo_metadata(false)
+        .log_sink(move |level, message| sink.lock().unwrap().push((level, message.to_string())))
+        .is_flag_supported("-Wall")
+        .unwrap();
+
+    // The variables are looked up by the build checking the flag.
+    let messages = messages.lock().unwrap();
+    assert!(messages
+        .iter()
+        .any(|(level, m)| *level == cc::LogLevel::Debug && m.starts_with("CFLAGS = ")));
+}
+
+#[test]
+fn gnu_runner() {
+    use std::io;
+    use std::process::Output;
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl cc::Runner for Recorder {
+        fn run(&self, cmd: &mut cc::Invocation) -> io::Result<Output> {
+            let program = cmd.get_program().to_string_lossy().into_owned();
+            self.0.lock().unwrap().push(program);
+            cmd.arg("-DREWRITTEN");
+            cc::ProcessRunner.run(cmd)
+        }
+    }
+
+    let test = Test::gnu();
+    let programs = Arc::new(Mutex::new(Vec::new()));
+    test.gcc()
+        .runner(Recorder(programs.clone()))
+        .flag("-fdiagnostics-format=text")
+        .file("foo.c")
+        .compile("foo");
+
+    assert_eq!(*programs.lock().unwrap(), ["cc", "ar"]);
+    test.cmd(0).must_have("foo.c").must_have("-DREWRITTEN");
+}
+
+#[test]
+fn gnu_runner_finds_android_compiler() {
+    use std::io;
+    use std::process::Output;
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl cc::Runner for Recorder {
+        fn run(&self, cmd: &mut cc::Invocation) -> io::Result<Output> {
+            let mut command = cmd.get_program().to_string_lossy().into_owned();
+            if cmd.get_args().iter().any(|a| a == "--version") {
+                command.push_str(" --version");
+            }
+            self.0.lock().unwrap().push(command);
+            cc::ProcessRunner.run(cmd)
+        }
+    }
+
+    let test = Test::gnu();
+    test.shim("aarch64-linux-android-gcc").shim("llvm-ar");
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    test.gcc()
+        .runner(Recorder(commands.clone()))
+        .target("aarch64-linux-android")
+        .host("x86_64-unknown-linux-gnu")
+        .flag("-fdiagnostics-format=text")
+        .file("foo.c")
+        .compile("foo");
+
+    assert_eq!(
+        *commands.lock().unwrap(),
+        [
+            "aarch64-linux-android-gcc --version",
+            "aarch64-linux-android-gcc",
+            "llvm-ar",
+        ]
+    );
+    test.cmd(1).must_have("foo.c");
+}
+
+#[test]
+fn gnu_runner_checks_flags() {
+    use std::io;
+    use std::process::Output;
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl cc::Runner for Recorder {
+        fn run(&self, cmd: &mut cc::Invocation) -> io::Result<Output> {
+            let mut command = cmd.get_program().to_string_lossy().into_owned();
+            if cmd.get_args().iter().any(|a| a == "--version") {
+                command.push_str(" --version");
+            }
+            if cmd.get_args().iter().any(|a| a == "-Wall") {
+                command.push_str(" -Wall");
+            }
+            self.0.lock().unwrap().push(command);
+            cc::ProcessRunner.run(cmd)
+        }
+    }
+
+    let test = Test::gnu();
+    test.shim("aarch64-linux-android-gcc").shim("llvm-ar");
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    test.gcc()
+        .runner(Recorder(commands.clone()))
+        .target("aarch64-linux-android")
+        .host("x86_64-unknown-linux-gnu")
+        .flag("-fdiagnostics-format=text")
+        .flag_if_supported("-Wall")
+        .file("foo.c")
+        .compile("foo");
+
+    assert_eq!(
+        *commands.lock().unwrap(),
+        [
+            "aarch64-linux-android-gcc --version",
+            "aarch64-linux-android-gcc --version",
+            "aarch64-linux-android-gcc -Wall",
+            "aarch64-linux-android-gcc -Wall",
+            "llvm-ar",
+        ]
+    );
+    test.cmd(3).must_have("-Wall").must_have("foo.c");
+}
+
 #[test]
 fn gnu_set_stdlib() {
     let test = Test::gnu();
@@ -271,7 +1180,8 @@ fn gnu_flag_if_supported() {
         .flag_if_supported("-std=c++11")
         .compile("foo");
 
-    test.cmd(0)
+    // The first commands check the flags.
+    test.cmd(3)
         .must_have("-v")
         .must_have("-Wall")
         .must_not_have("-Wflag-does-not-exist")
@@ -290,7 +1200,7 @@ fn gnu_flag_if_supported_cpp() {
         .flag_if_supported("-std=c++11")
         .compile("foo");
 
-    test.cmd(0).must_have("-std=c++11");
+    test.cmd(1).must_have("-std=c++11").must_have("foo.cpp");
 }
 
 #[test]
@@ -305,6 +1215,95 @@ fn gnu_static() {
     test.cmd(0).must_have("-static").must_not_have("-shared");
 }
 
+#[test]
+fn gnu_sysroot() {
+    let test = Test::gnu();
+    test.gcc()
+        .sysroot("/path/to/sysroot")
+        .file("foo.c")
+        .compile("foo");
+
+    test.cmd(0).must_have("--sysroot=/path/to/sysroot");
+}
+
+#[test]
+fn gnu_android_ndk() {
+    if cfg!(windows) {
+        return;
+    }
+    let test = Test::gnu();
+    let bin = test
+        .td
+        .path()
+        .join("ndk/toolchains/llvm/prebuilt/linux-x86_64/bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    test.shim("ndk/toolchains/llvm/prebuilt/linux-x86_64/bin/aarch64-linux-android24-clang")
+        .shim("ndk/toolchains/llvm/prebuilt/linux-x86_64/bin/llvm-ar");
+
+    test.gcc()
+        .target("aarch64-linux-android")
+        .host("x86_64-unknown-linux-gnu")
+        .android_api_level(24)
+        .__set_env("ANDROID_NDK_HOME", test.td.path().join("ndk"))
+        .file("foo.c")
+        .compile("foo");
+
+    test.cmd(0)
+        .must_have("--target=aarch64-linux-android24")
+        .must_have("-fPIC");
+    test.cmd(1).must_have("crs");
+}
+
+#[test]
+fn gnu_wasm_system_clang() {
+    let test = Test::gnu();
+    test.shim("clang").shim("llvm-ar");
+
+    test.gcc()
+        .target("wasm32-unknown-unknown")
+        .host("x86_64-unknown-linux-gnu")
+        .__set_env("GCCTEST_TARGETS", "x86-64,wasm32,wasm64")
+        .file("foo.c")
+        .compile("foo");
+
+    test.cmd(0)
+        .must_have("--target=wasm32-unknown-unknown")
+        .must_not_have("-fPIC");
+
+    let err = test
+        .gcc()
+        .target("wasm32-unknown-unknown")
+        .host("x86_64-unknown-linux-gnu")
+        .file("foo.c")
+        .try_compile("foo")
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("does not support wasm32"));
+}
+
+#[test]
+fn gnu_wasi_sdk() {
+    if cfg!(windows) {
+        return;
+    }
+    let test = Test::gnu();
+    std::fs::create_dir_all(test.td.path().join("wasi-sdk/bin")).unwrap();
+    test.shim("wasi-sdk/bin/clang").shim("wasi-sdk/bin/llvm-ar");
+
+    test.gcc()
+        .target("wasm32-wasi")
+        .host("x86_64-unknown-linux-gnu")
+        .__set_env("WASI_SDK_PATH", test.td.path().join("wasi-sdk"))
+        .file("foo.c")
+        .compile("foo");
+
+    let sysroot = test.td.path().join("wasi-sdk/share/wasi-sysroot");
+    test.cmd(0)
+        .must_have("--target=wasm32-wasi")
+        .must_have(format!("--sysroot={}", sysroot.display()))
+        .must_not_have("-fPIC");
+    test.cmd(1).must_have("crs");
+}
+
 #[test]
 fn msvc_smoke() {
     let test = Test::msvc();
@@ -319,6 +1318,47 @@ fn msvc_smoke() {
     test.cmd(1).must_have(test.td.path().join("foo.o"));
 }
 
+#[test]
+fn msvc_reproducible() {
+    let test = Test::msvc();
+    test.gcc()
+        .reproducible(true)
+        .remap_path_prefix("C:\\src", "D:\\")
+        .file("foo.c")
+        .compile("foo");
+
+    test.cmd(0)
+        .must_have("/Brepro")
+        .must_have("/pathmap:C:\\src=D:\\");
+    test.cmd(1).must_have("/Brepro");
+}
+
+#[test]
+fn msvc_response_file() {
+    let test = Test::msvc();
+    let mut build = test.gcc();
+    for i in 0..400 {
+        build.object(format!("some\\objects\\{}.obj", i));
+    }
+    build
+        .object("quoted \"\\obj\\\\\".obj")
+        .file("foo.c")
+        .compile("foo");
+
+    let rsp = test.td.path().join("libfoo.a.args");
+    test.cmd(1).must_have(format!("@{}", rsp.display()));
+    let bytes = std::fs::read(&rsp).unwrap();
+    let utf16 = bytes
+        .chunks(2)
+        .map(|c| u16::from(c[0]) | u16::from(c[1]) << 8)
+        .collect::<Vec<_>>();
+    let args = String::from_utf16(&utf16).unwrap();
+    assert!(args.starts_with("\u{FEFF}\"/OUT:"));
+    assert!(args.contains("\n\"/nologo\"\n"));
+    assert!(args.contains("\n\"some\\objects\\399.obj\"\n"));
+    assert!(args.contains("\n\"quoted \\\"\\obj\\\\\\\\\\\".obj\"\n"));
+}
+
 #[test]
 fn msvc_opt_level_0() {
     let test = Test::msvc();
@@ -342,6 +1382,40 @@ fn msvc_include() {
     test.cmd(0).must_have("/I").must_have("foo/bar");
 }
 
+#[test]
+fn msvc_diagnostics() {
+    let test = Test::msvc();
+    let mut build = test.gcc();
+    build
+        .__set_env(
+            "GCCTEST_STDERR",
+            "cl : Command line warning D9002 : ignoring unknown option '/foo'\r\n",
+        )
+        .__set_env(
+            "GCCTEST_STDOUT",
+            "foo.c\r\n\
+             C:\\src\\foo.c(12,5): warning C4996: 'strcpy': This function may be unsafe.\r\n",
+        )
+        .file("foo.c")
+        .compile("foo");
+
+    let diagnostics = build.diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].file(), None);
+    assert_eq!(diagnostics[0].code(), Some("D9002"));
+    assert_eq!(
+        diagnostics[1].file(),
+        Some(std::path::Path::new("C:\\src\\foo.c"))
+    );
+    assert_eq!(diagnostics[1].line(), Some(12));
+    assert_eq!(diagnostics[1].column(), Some(5));
+    assert_eq!(diagnostics[1].code(), Some("C4996"));
+    assert_eq!(
+        diagnostics[1].message(),
+        "'strcpy': This function may be unsafe."
+    );
+}
+
 #[test]
 fn msvc_define() {
     let test = Test::msvc();
@@ -0,0 +1,74 @@
+extern crate cc;
+
+use cc::testing::Test;
+
+#[test]
+fn gnu_fake_toolchain() {
+    let test = Test::gnu();
+    let mut build = test.build();
+    build
+        .include("foo/bar")
+        .flag_if_supported("-Wfoo")
+        .file("foo.c")
+        .file("bar.c");
+    build.compile("foo");
+
+    // The files may be compiled in any order with the `parallel` feature.
+    let invocations = test.invocations();
+    assert_eq!(invocations.len(), 3);
+    let foo = invocations
+        .iter()
+        .find(|e| e.has("foo.c".as_ref()))
+        .unwrap();
+    foo.must_have("foo.c")
+        .must_have("-Wfoo")
+        .must_have_in_order("-I", "foo/bar")
+        .must_not_have("bar.c");
+    assert_eq!(test.cmd(2).program(), test.dir().join("ar"));
+    assert!(test.dir().join("libfoo.a").exists());
+    assert_eq!(build.report().objects()[0].size(), Some(0));
+}
+
+#[test]
+fn msvc_fake_toolchain_response_file() {
+    let test = Test::msvc();
+    let mut build = test.build();
+    for i in 0..200 {
+        build.define(&format!("LONG_DEFINITION_NUMBER_{}", i), "\"quoted value\"");
+    }
+    build.file("foo.c").compile("foo");
+
+    test.cmd(0)
+        .must_have("/DLONG_DEFINITION_NUMBER_199=\"quoted value\"")
+        .must_have("/c")
+        .must_have("foo.c");
+    assert!(test
+        .cmd(0)
+        .args()
+        .iter()
+        .all(|a| !a.to_string_lossy().starts_with('@')));
+    assert!(test.dir().join("foo.o.args").exists());
+    assert!(test.dir().join("foo.o").exists());
+    assert!(test.dir().join("foo.lib").exists());
+}
+
+#[test]
+fn msvc_fake_toolchain() {
+    let test = Test::msvc();
+    test.build()
+        .define("FOO", "bar")
+        .file("foo.c")
+        .compile("foo");
+
+    test.cmd(0).must_have("/DFOO=bar").must_have("/c");
+    assert!(test.dir().join("foo.lib").exists());
+}
+
+#[test]
+fn fake_toolchain_cleans_up() {
+    let test = Test::gnu();
+    let dir = test.dir().to_owned();
+    assert!(dir.is_dir());
+    drop(test);
+    assert!(!dir.exists());
+}